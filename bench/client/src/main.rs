@@ -23,6 +23,7 @@ use ss::{Socket, Plain, Blocking};
 
 
 const TOTAL_MESSAGES: usize = 100000;
+const LATENCY_SAMPLES: usize = 10000;
 
 static mut start_time: *mut Timespec = 0 as *mut Timespec;
 
@@ -44,8 +45,42 @@ fn main() {
 
     let client_clone = client.clone();
     let rx_thread = thread::spawn(move || reader_thread(client_clone));
-    thread::spawn(move || writer_thread(client));
+    let writer_client = client.clone();
+    let tx_thread = thread::spawn(move || writer_thread(writer_client));
     let _ = rx_thread.join();
+    let _ = tx_thread.join();
+
+    latency_test(client);
+}
+
+/// Sends one ping at a time, waiting for each pong, and reports the round-trip percentiles.
+fn latency_test(mut client: Client) {
+    let ping = SimpleFrame::new(&b"ping"[..]);
+    let mut samples = Vec::<u64>::with_capacity(LATENCY_SAMPLES);
+
+    for _ in 0..LATENCY_SAMPLES {
+        let start = time::precise_time_ns();
+        if let Err(e) = client.stream.b_send(&ping) {
+            error!("During send: {}", e);
+            return;
+        }
+        if let Err(e) = client.stream.b_recv() {
+            error!("During recv: {}", e);
+            return;
+        }
+        samples.push(time::precise_time_ns() - start);
+    }
+
+    samples.sort();
+    info!("{} round trips    p50: {}us    p99: {}us",
+          LATENCY_SAMPLES,
+          percentile(&samples, 50) / 1000,
+          percentile(&samples, 99) / 1000);
+}
+
+fn percentile(sorted_samples: &[u64], pct: usize) -> u64 {
+    let offset = (sorted_samples.len() * pct / 100).min(sorted_samples.len() - 1);
+    sorted_samples[offset]
 }
 
 fn reader_thread(mut client: Client) {
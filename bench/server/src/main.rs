@@ -15,12 +15,11 @@ extern crate simple_stream as ss;
 
 
 use std::mem;
-use std::io::Error;
-use std::sync::Arc;
-use std::cell::UnsafeCell;
+use std::io::{Error, IoSlice, Write};
+use std::borrow::Cow;
 use std::os::unix::io::{RawFd, AsRawFd};
 
-use hydrogen::{Stream as HydrogenStream, HydrogenSocket};
+use hydrogen::{Stream as HydrogenStream, HydrogenSocket, ConnectionId, ListenerId, ListenAddr};
 use ss::frame::Frame;
 use ss::frame::simple::{SimpleFrame, SimpleFrameBuilder};
 use ss::{Socket, Plain, NonBlocking, SocketOptions};
@@ -28,7 +27,8 @@ use ss::{Socket, Plain, NonBlocking, SocketOptions};
 
 
 struct Stream {
-    inner: Plain<Socket, SimpleFrameBuilder>
+    inner: Plain<Socket, SimpleFrameBuilder>,
+    socket: Socket
 }
 
 impl HydrogenStream for Stream {
@@ -44,9 +44,17 @@ impl HydrogenStream for Stream {
             Err(e) => Err(e)
         }
     }
-    fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
-        let frame = SimpleFrame::new(buf);
-        self.inner.nb_send(&frame)
+    fn encode<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Owned(SimpleFrame::new(buf).to_bytes())
+    }
+    fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.socket.write(buf)
+    }
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize, Error> {
+        hydrogen::writev(self.socket.as_raw_fd(), bufs)
+    }
+    fn send_file(&mut self, fd: RawFd, offset: u64, len: usize) -> Result<usize, Error> {
+        hydrogen::sendfile(self.socket.as_raw_fd(), fd, offset, len)
     }
     fn shutdown(&mut self) -> Result<(), Error> {
         self.inner.shutdown()
@@ -59,12 +67,18 @@ impl AsRawFd for Stream {
 
 struct Server;
 impl hydrogen::Handler for Server {
-    fn on_server_created(&mut self, fd: RawFd) {
+    type Context = ();
+
+    #[allow(unused_variables)]
+    fn on_server_created(&self, listener: ListenerId, fd: RawFd) {
         let mut socket = Socket::new(fd);
         let _ = socket.set_reuseaddr(true);
     }
 
-    fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<hydrogen::Stream>> {
+    #[allow(unused_variables)]
+    fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+        -> (Box<dyn hydrogen::Stream>, ())
+    {
         let mut socket = Socket::new(fd);
         let _ = socket.set_nonblocking();
         let _ = socket.set_keepalive(true);
@@ -78,35 +92,50 @@ impl hydrogen::Handler for Server {
                                               mem::size_of::<libc::c_int>() as u32);
         }
 
-        let plain_stream = Plain::<Socket, SimpleFrameBuilder>::new(socket);
+        let plain_stream = Plain::<Socket, SimpleFrameBuilder>::new(socket.clone());
         let stream = Stream {
-            inner: plain_stream
+            inner: plain_stream,
+            socket
         };
 
-        Arc::new(UnsafeCell::new(stream))
+        (Box::new(stream), ())
     }
 
     #[allow(unused_variables)]
-    fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>) {
+    fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>) {
         let mut pong = [0u8; 4];
         pong[0] = 'p' as u8;
         pong[1] = 'o' as u8;
         pong[2] = 'n' as u8;
         pong[3] = 'g' as u8;
 
-        socket.send(&pong[..]);
+        let _ = socket.send(&pong[..]);
     }
 
     #[allow(unused_variables)]
-    fn on_connection_removed(&mut self, fd: RawFd, err: Error) { }
+    fn on_connection_removed(&self, id: ConnectionId, err: Error) { }
 }
 
 fn main() {
     env_logger::init().unwrap();
-    hydrogen::begin(Box::new(Server), hydrogen::Config {
-        addr: "127.0.0.1".to_string(),
-        port: 1337,
+    let server = hydrogen::begin(Box::new(Server), hydrogen::Config {
+        listeners: vec![hydrogen::ListenerConfig {
+            id: ListenerId(0),
+            addr: ListenAddr::Tcp {
+                addr: "127.0.0.1".to_string(),
+                port: 1337,
+                v6_only: None
+            }
+        }],
         max_threads: 2,
-        pre_allocated: 100
-    });
+        reactors: 1,
+        pre_allocated: 100,
+        tx_high_watermark: 1024 * 1024,
+        tx_low_watermark: 64 * 1024,
+        idle_timeout: None,
+        first_byte_timeout: None,
+        write_stall_timeout: None,
+        handoff_path: None
+    }).unwrap();
+    server.join();
 }
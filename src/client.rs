@@ -0,0 +1,769 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+//! Outgoing connections, kept open to a set of endpoints by the same event loops, threadpool and
+//! `Stream`s a server uses.
+//!
+//! A client is started with `client::begin`, which returns a `Client` handle. Each call to
+//! `Client::connect` adds an endpoint the client stays connected to, reconnecting with an
+//! exponential backoff whenever a connect fails or an established connection is removed, until
+//! `Client::disconnect` is called for it. Events are reported to a `client::Handler`, which
+//! mirrors `hydrogen::Handler`.
+
+
+use std::{cmp, fmt, io, panic};
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::os::unix::io::RawFd;
+
+use types::*;
+use timer::TimerHandle;
+use config;
+use config::Config as ServerConfig;
+use server;
+use error::Error;
+use super::Stream;
+
+
+/// Identifier of an endpoint added through `Client::connect`. Every connection made to the
+/// endpoint, including each reconnect, is reported with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EndpointId(pub u64);
+
+impl fmt::Display for EndpointId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Configuration options for client
+pub struct Config {
+    /// The number of threads to use for I/O handling, shared by every reactor.
+    /// The lib itself makes use of one thread per reactor.
+    pub max_threads: usize,
+    /// The number of reactors, each an event loop with its own epoll instance and share of the
+    /// connections. Connections are handed to each reactor in turn.
+    pub reactors: usize,
+    /// The amount of pre-allocated slab space for connections, split between the reactors.
+    /// This should be, roughly, the number of endpoints expected.
+    pub pre_allocated: usize,
+    /// Bytes a connection may have waiting in its outbound queue before sends to it are refused
+    /// and `Handler::on_backpressure` is called.
+    pub tx_high_watermark: usize,
+    /// Bytes a backpressured connection's outbound queue must drain to before sends to it are
+    /// accepted again and `Handler::on_writable` is called.
+    pub tx_low_watermark: usize,
+    /// Connections with no reads or writes for this long are removed, with
+    /// `ErrorKind::TimedOut`. It also bounds how long a connect may take. None disables the
+    /// timeout.
+    pub idle_timeout: Option<Duration>,
+    /// Connections whose outbound queue has not been written to the socket at all for this
+    /// long are removed, with `ErrorKind::TimedOut`. None disables the timeout.
+    pub write_stall_timeout: Option<Duration>,
+    /// How long to wait before reconnecting to an endpoint whose connection was removed, or
+    /// whose connect failed. The wait doubles with every attempt in a row that fails, and is
+    /// reset once a connect succeeds.
+    pub reconnect_delay: Duration,
+    /// The longest the wait before reconnecting grows to.
+    pub max_reconnect_delay: Duration
+}
+
+impl Config {
+    /// Checks for option combinations the client is unable to start with.
+    pub fn validate(&self) -> Result<(), Error> {
+        config::validate_pool(&self.server_config())?;
+        if self.reconnect_delay == Duration::from_millis(0) {
+            return Err(Error::InvalidConfig(
+                "reconnect_delay must be greater than zero".to_string()));
+        }
+        if self.max_reconnect_delay < self.reconnect_delay {
+            return Err(Error::InvalidConfig(
+                "max_reconnect_delay must be at least reconnect_delay".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configuration of the server, without any listeners, the client runs on.
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            listeners: Vec::new(),
+            max_threads: self.max_threads,
+            reactors: self.reactors,
+            pre_allocated: self.pre_allocated,
+            tx_high_watermark: self.tx_high_watermark,
+            tx_low_watermark: self.tx_low_watermark,
+            idle_timeout: self.idle_timeout,
+            first_byte_timeout: None,
+            write_stall_timeout: self.write_stall_timeout,
+            handoff_path: None
+        }
+    }
+}
+
+/// Events reported to lib consumer by a client.
+///
+/// Callbacks are made from the event loops and the I/O threadpool, often at the same time, so
+/// any state the handler keeps must be safe to share between them.
+pub trait Handler : Send + Sync {
+    /// Per-connection state, created alongside the stream in `on_new_connection`, and reachable
+    /// from every `HydrogenSocket` for the connection through `HydrogenSocket::context`.
+    ///
+    /// Handlers without any per-connection state should use `()`.
+    type Context: Send + Sync + 'static;
+    /// This method is called at the start of every attempt to connect to `endpoint`, with the
+    /// connecting fd, which is non-blocking.
+    ///
+    /// Like `hydrogen::Handler::on_new_connection`, it returns the stream and context for the
+    /// connection. Each attempt is a new connection, with its own `id`.
+    fn on_new_connection(&self, endpoint: EndpointId, id: ConnectionId, fd: RawFd)
+        -> (Box<dyn Stream>, Self::Context);
+    /// This method is called once a connection to `endpoint` has been established. From then on
+    /// it is serviced exactly as an accepted connection is by a server.
+    #[allow(unused_variables)]
+    fn on_connected(&self, endpoint: EndpointId, socket: HydrogenSocket<Self::Context>) { }
+    /// This method is called when an attempt to connect to `endpoint` fails, with how long
+    /// until the next attempt. `retry_in` is None if no attempt follows, because the endpoint
+    /// has been disconnected, or the client is shutting down.
+    #[allow(unused_variables)]
+    fn on_connect_failed(&self, endpoint: EndpointId, err: io::Error, retry_in: Option<Duration>)
+    { }
+    /// This method is called whenever the `recv` call returns an Ok(_) result.
+    fn on_data_received(&self, socket: HydrogenSocket<Self::Context>, buf: Vec<u8>);
+    /// This method is called after an established connection to `endpoint` has been removed,
+    /// with the `std::io::Error` as the reason removed. A reconnect follows after
+    /// `Config::reconnect_delay`, unless the endpoint has been disconnected, or the client is
+    /// shutting down.
+    fn on_connection_removed(&self, endpoint: EndpointId, id: ConnectionId, err: io::Error);
+    /// This method is called when flushing a connection's buffered output fails, after
+    /// `HydrogenSocket::send` has already returned `SendStatus::Queued` for it.
+    #[allow(unused_variables)]
+    fn on_send_error(&self, id: ConnectionId, err: io::Error) { }
+    /// This method is called when a send fills a connection's outbound queue to
+    /// `Config::tx_high_watermark`, on the thread that made the send.
+    #[allow(unused_variables)]
+    fn on_backpressure(&self, id: ConnectionId) { }
+    /// This method is called once the outbound queue of a connection previously reported
+    /// through `on_backpressure` has drained to `Config::tx_low_watermark`.
+    #[allow(unused_variables)]
+    fn on_writable(&self, socket: HydrogenSocket<Self::Context>) { }
+    /// This method is called when the client hits an error it is unable to recover from.
+    ///
+    /// The client begins shutting down immediately after this call, exactly as if
+    /// `Client::shutdown` had been called.
+    #[allow(unused_variables)]
+    fn on_client_error(&self, err: Error) { }
+    /// This method is called after any other callback panics, with `Error::HandlerPanic`
+    /// describing the panic, and the connection the callback was made for, if any.
+    ///
+    /// A panic in `on_new_connection` fails that attempt to connect, which is retried as any
+    /// other failed attempt is.
+    #[allow(unused_variables)]
+    fn on_handler_panic(&self, id: Option<ConnectionId>, err: Error) { }
+}
+
+/// Starts a client with the passed configuration and handler.
+///
+/// The client runs on background threads, and has no endpoints until `Client::connect` is
+/// called. Any failure while starting is returned here and nothing is left running.
+pub fn begin<T>(handler: Box<T>, cfg: Config) -> Result<Client<T::Context>, Error>
+    where T: Handler + 'static
+{
+    info!("Starting client...");
+    cfg.validate()?;
+
+    let handler: Box<dyn Handler<Context = T::Context>> = handler;
+    let shared = Arc::new(Shared {
+        handler,
+        server: Mutex::new(None),
+        endpoints: Mutex::new(Endpoints {
+            next_id: 1,
+            by_id: HashMap::new(),
+            by_connection: HashMap::new()
+        }),
+        reconnect_delay: cfg.reconnect_delay,
+        max_reconnect_delay: cfg.max_reconnect_delay
+    });
+
+    let adapter: Box<dyn ErasedHandler> = Box::new(Adapter {
+        shared: Arc::downgrade(&shared)
+    });
+    let server = server::start(cfg.server_config(), Arc::from(adapter))?;
+
+    { // Mutex lock
+        let mut slot = match shared.server.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        *slot = Some(server.clone());
+    } // Mutex unlock
+
+    Ok(Client {
+        shared,
+        server
+    })
+}
+
+/// Handle to a running client, returned from `client::begin`.
+///
+/// Handles are cheap to clone, and every method may be called from any thread. `C` is the
+/// `Handler::Context` type of the client.
+///
+/// Dropping every handle shuts the client down, as `shutdown` does, except that the handler is
+/// dropped along with the last handle, so nothing is reported from then on.
+pub struct Client<C: 'static = ()> {
+    shared: Arc<Shared<C>>,
+    /// The server, without any listeners, the client's connections belong to
+    server: ServerHandle<C>
+}
+
+impl<C: 'static> Clone for Client<C> {
+    fn clone(&self) -> Client<C> {
+        Client {
+            shared: self.shared.clone(),
+            server: self.server.clone()
+        }
+    }
+}
+
+impl<C: Send + Sync + 'static> Client<C> {
+    /// Adds `addr` as an endpoint, and starts connecting to it on the I/O threadpool.
+    ///
+    /// The client stays connected to the endpoint until `disconnect` is called for it. Fails
+    /// with `Error::Connect` once the client is shutting down.
+    pub fn connect(&self, addr: SocketAddr) -> Result<EndpointId, Error> {
+        let server = match self.shared.server() {
+            Some(server) => server,
+            None => {
+                return Err(Error::Connect(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                                         "Client shutdown")));
+            }
+        };
+
+        let endpoint;
+        { // Mutex lock
+            let mut endpoints = self.shared.lock_endpoints();
+
+            // The attempt waits on the lock, so it always finds the endpoint
+            endpoint = EndpointId(endpoints.next_id);
+            endpoints.next_id += 1;
+            let timer = schedule_attempt(&self.shared, &server, endpoint, Duration::from_millis(0));
+            endpoints.by_id.insert(endpoint, Endpoint {
+                addr,
+                connection: None,
+                connected: false,
+                backoff: self.shared.reconnect_delay,
+                timer: Some(timer)
+            });
+        } // Mutex unlock
+
+        debug!("Added endpoint {}: {}", endpoint, addr);
+        Ok(endpoint)
+    }
+
+    /// Removes the endpoint, closing its connection, and stops reconnecting to it.
+    ///
+    /// An established connection is reported to `Handler::on_connection_removed`, and an attempt
+    /// still connecting to `Handler::on_connect_failed`, with `ErrorKind::ConnectionAborted`.
+    pub fn disconnect(&self, endpoint: EndpointId) {
+        let removed = { // Mutex lock
+            let mut endpoints = self.shared.lock_endpoints();
+
+            endpoints.by_id.remove(&endpoint)
+        }; // Mutex unlock
+
+        let removed = match removed {
+            Some(removed) => removed,
+            None => return
+        };
+        debug!("Removed endpoint {}", endpoint);
+
+        if let Some(timer) = removed.timer {
+            timer.cancel();
+        }
+        if let Some(socket) = removed.connection.and_then(|id| self.server.socket(id)) {
+            socket.close(io::Error::new(io::ErrorKind::ConnectionAborted, "Endpoint disconnected"));
+        }
+    }
+
+    /// Returns a socket for the endpoint's connection, or None if it is not connected right now.
+    pub fn socket(&self, endpoint: EndpointId) -> Option<HydrogenSocket<C>> {
+        let id = { // Mutex lock
+            let endpoints = self.shared.lock_endpoints();
+
+            match endpoints.by_id.get(&endpoint) {
+                Some(&Endpoint { connection: Some(id), connected: true, .. }) => id,
+                _ => return None
+            }
+        }; // Mutex unlock
+
+        self.server.socket(id)
+    }
+
+    /// Signals the client to stop.
+    ///
+    /// Reconnecting stops, in-flight I/O is allowed to finish, then every connection is closed
+    /// and reported through `Handler::on_connection_removed`, or `Handler::on_connect_failed`
+    /// if it had not yet connected. This method does not block, use `join` to wait for the
+    /// shutdown to complete.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+        self.server.shutdown();
+    }
+
+    /// Blocks until the client has completely stopped.
+    ///
+    /// This will block forever unless `shutdown` has been, or will be, called, or the client hits
+    /// a fatal error reported through `Handler::on_client_error`.
+    pub fn join(&self) {
+        self.server.join();
+    }
+}
+
+/// An address the client keeps a connection to.
+struct Endpoint {
+    addr: SocketAddr,
+    /// The connection made by the latest attempt, until it is removed
+    connection: Option<ConnectionId>,
+    /// Set once the connection has finished connecting
+    connected: bool,
+    /// How long to wait before the next reconnect
+    backoff: Duration,
+    /// The next attempt to connect, while one is waiting to run
+    timer: Option<TimerHandle>
+}
+
+struct Endpoints {
+    next_id: u64,
+    by_id: HashMap<EndpointId, Endpoint>,
+    /// Endpoint of every connection not yet reported removed, including those of endpoints that
+    /// have since been disconnected
+    by_connection: HashMap<ConnectionId, EndpointId>
+}
+
+/// Owned by the client's handles, and reached by the server it runs on through a `Weak`, so it
+/// is dropped along with the last handle.
+struct Shared<C: 'static> {
+    handler: Box<dyn Handler<Context = C>>,
+    /// The server the client runs on, taken out once it is shutting down
+    server: Mutex<Option<ServerHandle<C>>>,
+    endpoints: Mutex<Endpoints>,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration
+}
+
+impl<C: Send + Sync + 'static> Shared<C> {
+    fn server(&self) -> Option<ServerHandle<C>> {
+        let slot = match self.server.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        slot.clone()
+    }
+
+    /// Stops any further attempts to connect.
+    fn stop(&self) {
+        let server = { // Mutex lock
+            let mut slot = match self.server.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            slot.take()
+        }; // Mutex unlock
+        drop(server);
+
+        let endpoints = self.lock_endpoints();
+        for endpoint in endpoints.by_id.values() {
+            if let Some(ref timer) = endpoint.timer {
+                timer.cancel();
+            }
+        }
+    }
+
+    fn lock_endpoints(&self) -> MutexGuard<'_, Endpoints> {
+        match self.endpoints.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        }
+    }
+
+    /// Records that connection `id` has connected, and returns its endpoint, unless the
+    /// endpoint has since been disconnected.
+    fn connected(&self, id: ConnectionId) -> Option<EndpointId> {
+        let mut endpoints = self.lock_endpoints();
+
+        let endpoint = *endpoints.by_connection.get(&id)?;
+        let state = endpoints.by_id.get_mut(&endpoint)?;
+        state.connected = true;
+        state.backoff = self.reconnect_delay;
+
+        Some(endpoint)
+    }
+
+    /// Returns the endpoint connection `id` was made for, unless it has been reported removed.
+    fn endpoint_of(&self, id: ConnectionId) -> Option<EndpointId> {
+        let endpoints = self.lock_endpoints();
+
+        endpoints.by_connection.get(&id).cloned()
+    }
+}
+
+impl<C: 'static> Drop for Shared<C> {
+    fn drop(&mut self) {
+        // Every handle is gone, so nothing else could ever shut the server down
+        let server = match self.server.get_mut() {
+            Ok(slot) => slot.take(),
+            Err(p) => p.into_inner().take()
+        };
+        if let Some(server) = server {
+            debug!("Every client handle dropped, shutting down");
+            server.shutdown();
+        }
+    }
+}
+
+/// Schedules an attempt to connect to the endpoint once `delay` has passed.
+fn schedule_attempt<C>(shared: &Arc<Shared<C>>,
+                       server: &ServerHandle<C>,
+                       endpoint: EndpointId,
+                       delay: Duration)
+                       -> TimerHandle
+    where C: Send + Sync + 'static
+{
+    // Pending timers are only dropped along with the server, so they must not keep the client
+    // from being dropped along with its last handle
+    let weak: Weak<Shared<C>> = Arc::downgrade(shared);
+    server.schedule(delay, move || {
+        if let Some(shared) = weak.upgrade() {
+            attempt(&shared, endpoint);
+        }
+    })
+}
+
+/// Starts connecting to the endpoint, unless it has since been disconnected. Called from the
+/// I/O threadpool.
+fn attempt<C: Send + Sync + 'static>(shared: &Arc<Shared<C>>, endpoint: EndpointId) {
+    let server = match shared.server() {
+        Some(server) => server,
+        None => return
+    };
+    let addr = { // Mutex lock
+        let mut endpoints = shared.lock_endpoints();
+
+        match endpoints.by_id.get_mut(&endpoint) {
+            Some(state) => {
+                state.timer = None;
+                state.addr
+            }
+            None => return
+        }
+    }; // Mutex unlock
+
+    debug!("Connecting endpoint {} to {}", endpoint, addr);
+    let mut recorded = None;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        server.connect(addr, |id, fd| {
+            let stream_and_context = shared.handler.on_new_connection(endpoint, id, fd);
+
+            // The outcome may be reported as soon as the connection reaches its reactor, before
+            // connect returns, so it is recorded here, and forgotten again if connect fails
+            let mut endpoints = shared.lock_endpoints();
+            endpoints.by_connection.insert(id, endpoint);
+            if let Some(state) = endpoints.by_id.get_mut(&endpoint) {
+                state.connection = Some(id);
+                state.connected = false;
+            }
+            recorded = Some(id);
+
+            stream_and_context
+        })
+    }));
+
+    let err = match result {
+        Ok(Ok(_)) => return,
+        Ok(Err(Error::Connect(err))) => err,
+        Ok(Err(err)) => io::Error::other(err),
+        Err(payload) => {
+            let msg = report_panic(&*shared.handler, "on_new_connection", &*payload);
+            io::Error::other(Error::HandlerPanic("on_new_connection", msg))
+        }
+    };
+
+    debug!("Connecting endpoint {} failed: {}", endpoint, err);
+    let retry_in = reconnect_later(shared, endpoint, recorded);
+    call_handler(&*shared.handler, "on_connect_failed", || {
+        shared.handler.on_connect_failed(endpoint, err, retry_in)
+    });
+}
+
+/// Forgets the endpoint's connection, if it still has `id`, and schedules the next attempt to
+/// connect to it. Returns how long until then, or None if the endpoint has been disconnected,
+/// or the client is shutting down.
+fn reconnect_later<C>(shared: &Arc<Shared<C>>, endpoint: EndpointId, id: Option<ConnectionId>)
+    -> Option<Duration>
+    where C: Send + Sync + 'static
+{
+    let server = shared.server();
+    let mut endpoints = shared.lock_endpoints();
+
+    if let Some(id) = id {
+        endpoints.by_connection.remove(&id);
+    }
+    let state = endpoints.by_id.get_mut(&endpoint)?;
+    if id.is_some() && state.connection != id {
+        return None;
+    }
+    state.connection = None;
+    state.connected = false;
+
+    let server = server?;
+    let delay = state.backoff;
+    state.backoff = cmp::min(state.backoff * 2, shared.max_reconnect_delay);
+    state.timer = Some(schedule_attempt(shared, &server, endpoint, delay));
+
+    Some(delay)
+}
+
+/// Makes a handler callback from outside the server's own callbacks, catching any panic and
+/// reporting it to `Handler::on_handler_panic`.
+fn call_handler<C, F>(handler: &dyn Handler<Context = C>, callback: &'static str, f: F)
+    where C: Send + Sync + 'static,
+          F: FnOnce()
+{
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+        report_panic(handler, callback, &*payload);
+    }
+}
+
+/// Reports a panic in `callback` to `Handler::on_handler_panic`, and returns its message.
+fn report_panic<C>(handler: &dyn Handler<Context = C>,
+                   callback: &'static str,
+                   payload: &(dyn Any + Send))
+                   -> String
+    where C: Send + Sync + 'static
+{
+    let msg = server::panic_message(payload);
+    error!("Handler panicked in {}: {}", callback, msg);
+
+    let err = Error::HandlerPanic(callback, msg.clone());
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handler.on_handler_panic(None, err)));
+    if result.is_err() {
+        error!("Handler panicked in on_handler_panic");
+    }
+
+    msg
+}
+
+/// The server handler a client runs on, which keeps track of each connection's endpoint before
+/// passing events on to the client's own handler. Once every client handle has been dropped,
+/// events are no longer passed on.
+struct Adapter<C: 'static> {
+    shared: Weak<Shared<C>>
+}
+
+impl<C: Send + Sync + 'static> super::Handler for Adapter<C> {
+    type Context = C;
+
+    fn on_server_created(&self, _: ListenerId, _: RawFd) { }
+
+    fn on_new_connection(&self, _: ConnectionId, _: ListenerId, _: RawFd)
+        -> (Box<dyn Stream>, C)
+    {
+        unreachable!("Clients have no listeners")
+    }
+
+    fn on_data_received(&self, socket: HydrogenSocket<C>, buf: Vec<u8>) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.handler.on_data_received(socket, buf)
+        }
+    }
+
+    fn on_connect_result(&self, id: ConnectionId, result: Result<HydrogenSocket<C>, io::Error>) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return
+        };
+        match result {
+            Ok(socket) => {
+                // Disconnected before the connection could be closed along with its endpoint
+                let endpoint = match shared.connected(id) {
+                    Some(endpoint) => endpoint,
+                    None => {
+                        socket.close(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                                    "Endpoint disconnected"));
+                        return;
+                    }
+                };
+                debug!("Endpoint {} connected", endpoint);
+                shared.handler.on_connected(endpoint, socket);
+            }
+            Err(err) => {
+                let endpoint = match shared.endpoint_of(id) {
+                    Some(endpoint) => endpoint,
+                    None => return
+                };
+                debug!("Connecting endpoint {} failed: {}", endpoint, err);
+                let retry_in = reconnect_later(&shared, endpoint, Some(id));
+                shared.handler.on_connect_failed(endpoint, err, retry_in);
+            }
+        }
+    }
+
+    fn on_connection_removed(&self, id: ConnectionId, err: io::Error) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return
+        };
+        let endpoint = match shared.endpoint_of(id) {
+            Some(endpoint) => endpoint,
+            None => return
+        };
+        debug!("Endpoint {} lost its connection: {}", endpoint, err);
+        reconnect_later(&shared, endpoint, Some(id));
+        shared.handler.on_connection_removed(endpoint, id, err);
+    }
+
+    fn on_send_error(&self, id: ConnectionId, err: io::Error) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.handler.on_send_error(id, err)
+        }
+    }
+
+    fn on_backpressure(&self, id: ConnectionId) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.handler.on_backpressure(id)
+        }
+    }
+
+    fn on_writable(&self, socket: HydrogenSocket<C>) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.handler.on_writable(socket)
+        }
+    }
+
+    fn on_server_error(&self, err: Error) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.stop();
+            shared.handler.on_client_error(err)
+        }
+    }
+
+    fn on_handler_panic(&self, id: Option<ConnectionId>, err: Error) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.handler.on_handler_panic(id, err)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    /// Stream for connections that are never expected to connect
+    struct Unconnected {
+        fd: RawFd
+    }
+
+    impl AsRawFd for Unconnected {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd
+        }
+    }
+
+    impl Stream for Unconnected {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, io::Error> {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+
+        fn send(&mut self, _: &[u8]) -> Result<usize, io::Error> {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+
+        fn shutdown(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    /// Records the wait reported with every failed attempt to connect
+    #[derive(Default)]
+    struct Retries {
+        retry_in: Mutex<Vec<Option<Duration>>>
+    }
+
+    impl Handler for Arc<Retries> {
+        type Context = ();
+
+        fn on_new_connection(&self, _: EndpointId, _: ConnectionId, fd: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            (Box::new(Unconnected { fd }), ())
+        }
+
+        fn on_connect_failed(&self, _: EndpointId, _: io::Error, retry_in: Option<Duration>) {
+            self.retry_in.lock().unwrap().push(retry_in);
+        }
+
+        fn on_data_received(&self, _: HydrogenSocket, _: Vec<u8>) { }
+        fn on_connection_removed(&self, _: EndpointId, _: ConnectionId, _: io::Error) { }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn reconnect_backoff_is_capped() {
+        let retries = Arc::new(Retries::default());
+        let client = begin(Box::new(retries.clone()), Config {
+            max_threads: 2,
+            reactors: 1,
+            pre_allocated: 8,
+            tx_high_watermark: 1 << 20,
+            tx_low_watermark: 1 << 10,
+            idle_timeout: None,
+            write_stall_timeout: None,
+            reconnect_delay: ms(10),
+            max_reconnect_delay: ms(40)
+        }).unwrap();
+
+        // Nothing listens on a port just released
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let endpoint = client.connect(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while retries.retry_in.lock().unwrap().len() < 5 {
+            assert!(Instant::now() < deadline, "Timed out waiting");
+            thread::sleep(ms(5));
+        }
+        client.disconnect(endpoint);
+
+        // Attempts stop once disconnected, any still underway being reported with None
+        thread::sleep(ms(100));
+        let retry_in = retries.retry_in.lock().unwrap().clone();
+        assert_eq!(&retry_in[..3], &[Some(ms(10)), Some(ms(20)), Some(ms(40))]);
+        assert!(retry_in[3..].iter().all(|&wait| wait.is_none() || wait == Some(ms(40))));
+        thread::sleep(ms(100));
+        assert_eq!(retries.retry_in.lock().unwrap().len(), retry_in.len());
+
+        client.shutdown();
+        client.join();
+    }
+}
@@ -6,17 +6,197 @@
 // http://mozilla.org/MPL/2.0/.
 
 
+use std::path::PathBuf;
+use std::time::Duration;
+use std::collections::HashSet;
+use std::os::unix::io::RawFd;
+
+use error::Error;
+use types::ListenerId;
+
+
+/// A socket for the server to accept connections on.
+pub struct ListenerConfig {
+    /// Reported to `Handler::on_new_connection` for every connection accepted through this
+    /// listener. Must be unique within the `Config`.
+    pub id: ListenerId,
+    /// What to bind to, or adopt
+    pub addr: ListenAddr
+}
+
+/// Address a listener is bound to, or the already bound socket it adopts.
+pub enum ListenAddr {
+    /// A TCP socket.
+    Tcp {
+        /// An IPv4 or IPv6 address, or a hostname
+        addr: String,
+        port: u16,
+        /// For IPv6 addresses, whether `IPV6_V6ONLY` is set. `Some(false)` binds a dual-stack
+        /// socket that also accepts IPv4 connections, None leaves the system default. Ignored
+        /// for IPv4 addresses.
+        v6_only: Option<bool>
+    },
+    /// A Unix stream socket with a socket file at `path`.
+    ///
+    /// A socket file left behind at `path` by a process that is no longer listening on it is
+    /// replaced. The file is removed again once the server shuts down.
+    Unix {
+        path: PathBuf,
+        /// Permissions the socket file is given before any connection is accepted. None
+        /// leaves them to the process umask.
+        mode: Option<u32>
+    },
+    /// A Unix stream socket in the Linux abstract namespace, which has no socket file.
+    /// `name` excludes the leading nul byte.
+    UnixAbstract {
+        name: Vec<u8>
+    },
+    /// An already bound and listening stream socket, such as one inherited from a parent
+    /// process, of any address family.
+    ///
+    /// Once the fd has been checked to be a listening stream socket, it is owned by the server,
+    /// which closes it when it shuts down, or fails to start. An fd that fails the check is
+    /// left open. `on_server_created` is still called with the fd, after it has been bound.
+    Fd {
+        fd: RawFd
+    },
+    /// Every socket passed through systemd socket activation, in `LISTEN_FDS`, with this name
+    /// in `LISTEN_FDNAMES`, which is the socket unit's `FileDescriptorName=`. Sockets systemd
+    /// has not named are named "unknown".
+    ///
+    /// A unit with several `Listen*=` lines passes a socket for each, under the same name. Each
+    /// is adopted as with `ListenAddr::Fd`, and all are reported under this listener's id.
+    ///
+    /// The variables systemd passes the sockets in are left in the environment, unless
+    /// `unset_env` is set on any `Systemd` listener.
+    Systemd {
+        name: String,
+        /// Removes `LISTEN_FDS`, `LISTEN_PID` and `LISTEN_FDNAMES` from the environment once
+        /// the listeners have been opened, so they are not inherited by child processes.
+        ///
+        /// Changing the environment is not thread safe. Only set this when the server is started
+        /// before any other thread of the process, including those of other servers.
+        unset_env: bool
+    }
+}
+
 /// Configuration options for server
 pub struct Config {
-    /// Address to bind to
-    pub addr: String,
-    /// Port to bind to
-    pub port: u16,
-    /// The number of threads to use for I/O handling.
-    /// The lib itself makes use of 3 threads.
+    /// Sockets to accept connections on. Every connection accepted, whichever listener it came
+    /// through, shares the same connection pool, threads and handler.
+    pub listeners: Vec<ListenerConfig>,
+    /// The number of threads to use for I/O handling, shared by every reactor.
+    /// The lib itself makes use of one thread per listening socket, plus one per reactor.
     pub max_threads: usize,
-    /// The amount of pre-allocated slab space for connections.
+    /// The number of reactors, each an event loop with its own epoll instance and share of the
+    /// connections. TCP listeners bind a `SO_REUSEPORT` socket for each reactor, so the kernel
+    /// spreads incoming connections across them. Connections accepted on any other listener
+    /// are handed to each reactor in turn.
+    pub reactors: usize,
+    /// The amount of pre-allocated slab space for connections, split between the reactors.
     /// This should be, roughly, the maximum amount of concurrent
     /// connections expected.
-    pub pre_allocated: usize
+    pub pre_allocated: usize,
+    /// Bytes a connection may have waiting in its outbound queue before sends to it are refused
+    /// and `Handler::on_backpressure` is called.
+    pub tx_high_watermark: usize,
+    /// Bytes a backpressured connection's outbound queue must drain to before sends to it are
+    /// accepted again and `Handler::on_writable` is called.
+    pub tx_low_watermark: usize,
+    /// Connections with no reads or writes for this long are removed, with
+    /// `ErrorKind::TimedOut`. None disables the timeout.
+    pub idle_timeout: Option<Duration>,
+    /// Connections that have not sent anything this long after being accepted are removed,
+    /// with `ErrorKind::TimedOut`. None disables the timeout.
+    pub first_byte_timeout: Option<Duration>,
+    /// Connections whose outbound queue has not been written to the socket at all for this
+    /// long are removed, with `ErrorKind::TimedOut`. None disables the timeout.
+    pub write_stall_timeout: Option<Duration>,
+    /// Unix socket used to restart without dropping connections. None disables handoffs.
+    ///
+    /// While running, the server listens here for a successor, a process of the same user
+    /// starting with the same `handoff_path`. When one connects, the server stops, and hands
+    /// it every listening socket, along with any connections `Handler::on_connection_handoff`
+    /// chooses to hand off, then finishes shutting down. The successor's `hydrogen::begin`
+    /// blocks until then, and uses each listening socket in place of binding the listener with
+    /// the same id.
+    pub handoff_path: Option<PathBuf>
+}
+
+impl Config {
+    /// Checks for option combinations the server is unable to start with.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.listeners.is_empty() {
+            return Err(Error::InvalidConfig("listeners must not be empty".to_string()));
+        }
+        let mut ids = HashSet::<ListenerId>::with_capacity(self.listeners.len());
+        let mut fds = HashSet::<RawFd>::new();
+        let mut systemd_names = HashSet::<&str>::new();
+        for listener in self.listeners.iter() {
+            if !ids.insert(listener.id) {
+                return Err(Error::InvalidConfig(
+                    format!("listener id {} is used more than once", listener.id)));
+            }
+            let empty = match listener.addr {
+                ListenAddr::Tcp { .. } | ListenAddr::Fd { .. } => false,
+                ListenAddr::Unix { ref path, .. } => path.as_os_str().is_empty(),
+                ListenAddr::UnixAbstract { ref name } => name.is_empty(),
+                ListenAddr::Systemd { ref name, .. } => name.is_empty()
+            };
+            if empty {
+                return Err(Error::InvalidConfig(
+                    format!("listener {} has an empty socket path or name", listener.id)));
+            }
+            // The same socket must never be owned, and closed, by two listeners
+            let duplicate = match listener.addr {
+                ListenAddr::Fd { fd } if fd < 0 => {
+                    return Err(Error::InvalidConfig(
+                        format!("listener {} has a negative fd", listener.id)));
+                }
+                ListenAddr::Fd { fd } => !fds.insert(fd),
+                ListenAddr::Systemd { ref name, .. } => !systemd_names.insert(name),
+                _ => false
+            };
+            if duplicate {
+                return Err(Error::InvalidConfig(
+                    format!("listener {} adopts a socket used by another listener", listener.id)));
+            }
+        }
+        validate_pool(self)?;
+        if self.handoff_path.as_ref().is_some_and(|path| path.as_os_str().is_empty()) {
+            return Err(Error::InvalidConfig("handoff_path must not be empty".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the options that do not concern listeners, which are all a client has.
+pub fn validate_pool(cfg: &Config) -> Result<(), Error> {
+    if cfg.max_threads == 0 {
+        return Err(Error::InvalidConfig("max_threads must be at least 1".to_string()));
+    }
+    if cfg.reactors == 0 {
+        return Err(Error::InvalidConfig("reactors must be at least 1".to_string()));
+    }
+    if cfg.pre_allocated == 0 {
+        return Err(Error::InvalidConfig("pre_allocated must be at least 1".to_string()));
+    }
+    if cfg.tx_high_watermark == 0 {
+        return Err(Error::InvalidConfig("tx_high_watermark must be at least 1".to_string()));
+    }
+    if cfg.tx_low_watermark >= cfg.tx_high_watermark {
+        return Err(Error::InvalidConfig(
+            "tx_low_watermark must be less than tx_high_watermark".to_string()));
+    }
+    let timeouts = [("idle_timeout", cfg.idle_timeout),
+                    ("first_byte_timeout", cfg.first_byte_timeout),
+                    ("write_stall_timeout", cfg.write_stall_timeout)];
+    for &(name, timeout) in timeouts.iter() {
+        if timeout == Some(Duration::from_millis(0)) {
+            return Err(Error::InvalidConfig(format!("{} must be greater than zero", name)));
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,199 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::mem;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{RawFd, AsRawFd};
+
+use libc;
+
+use slab::Token;
+
+
+// When added to epoll, these will be the conditions of kernel notification:
+//
+// EPOLLIN          - Data is available in kernel buffer.
+// EPOLLRDHUP       - Peer closed connection.
+// EPOLLET          - Register in EdgeTrigger mode.
+// EPOLLONESHOT     - After an event is pulled out with epoll_wait(2) the associated
+//                    file descriptor is internally disabled and no other events will
+//                    be reported by the epoll interface.
+pub const DEFAULT_EVENTS: i32 = libc::EPOLLIN |
+                                libc::EPOLLRDHUP |
+                                libc::EPOLLET |
+                                libc::EPOLLONESHOT;
+
+// Epoll user data for the wake eventfd. Slab offsets never reach u32::MAX, so no Connection's
+// token can collide with it.
+pub const WAKE_TOKEN: Token = u64::MAX;
+
+/// An epoll instance, along with the eventfd used to interrupt `wait`.
+///
+/// Both fds are closed once the last reference is dropped, so anything still holding on to the
+/// instance, such as a removed connection, never touches an fd that has since been reused.
+pub struct Epoll {
+    fd: RawFd,
+    /// Eventfd in the interest list under `WAKE_TOKEN`, written to by `wake`
+    wake_fd: RawFd
+}
+
+impl Epoll {
+    /// Creates an epoll instance, with its wake eventfd already in the interest list.
+    pub fn new() -> Result<Epoll, Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let wake_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if wake_fd < 0 {
+            let err = Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+
+        // Dropped on failure, which closes both
+        let epoll = Epoll {
+            fd,
+            wake_fd
+        };
+        epoll.ctl(libc::EPOLL_CTL_ADD, wake_fd, libc::EPOLLIN, WAKE_TOKEN)?;
+
+        Ok(epoll)
+    }
+
+    /// Adds `fd` to the interest list, reporting `events` with `token` as the user data.
+    pub fn add(&self, fd: RawFd, events: i32, token: Token) -> Result<(), Error> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    /// Replaces the event mask of `fd`, re-arming it if it was added with `EPOLLONESHOT`.
+    pub fn modify(&self, fd: RawFd, events: i32, token: Token) -> Result<(), Error> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    /// Waits up to `timeout` milliseconds, or forever if negative, for events, and returns how
+    /// many were written to the start of `events`.
+    pub fn wait(&self, events: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+        let result = unsafe {
+            libc::epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, timeout)
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(result as usize)
+    }
+
+    /// Interrupts `wait`, so newly accepted connections, connections in an error'd state, and
+    /// shutdown requests are dealt with immediately.
+    pub fn wake(&self) {
+        let buf = 1u64;
+        let result = unsafe {
+            libc::write(self.wake_fd,
+                        &buf as *const u64 as *const libc::c_void,
+                        mem::size_of::<u64>())
+        };
+
+        if result < 0 {
+            // The counter being full means a wake up is already pending
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::WouldBlock {
+                error!("Writing to wake eventfd: {}", err);
+            }
+        }
+    }
+
+    /// Resets the wake eventfd's counter, so it is not reported again until the next wake up.
+    pub fn clear_wake(&self) {
+        let mut buf = 0u64;
+        let _ = unsafe {
+            libc::read(self.wake_fd,
+                       &mut buf as *mut u64 as *mut libc::c_void,
+                       mem::size_of::<u64>())
+        };
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: i32, token: Token) -> Result<(), Error> {
+        let mut event = libc::epoll_event {
+            events: events as u32,
+            u64: token
+        };
+        if unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        if unsafe { libc::close(self.fd) } < 0 {
+            error!("Closing epoll instance: {}", Error::last_os_error());
+        }
+        if unsafe { libc::close(self.wake_fd) } < 0 {
+            error!("Closing wake eventfd: {}", Error::last_os_error());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn empty_events() -> Vec<libc::epoll_event> {
+        vec![libc::epoll_event { events: 0, u64: 0 }; 4]
+    }
+
+    #[test]
+    fn wake_interrupts_blocked_wait() {
+        let epoll = Arc::new(Epoll::new().unwrap());
+        let waker = {
+            let epoll = epoll.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                epoll.wake();
+            })
+        };
+
+        let started = Instant::now();
+        let mut events = empty_events();
+        assert_eq!(epoll.wait(&mut events[..], 10_000).unwrap(), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let token = events[0].u64;
+        assert_eq!(token, WAKE_TOKEN);
+        waker.join().unwrap();
+    }
+
+    #[test]
+    fn wakes_are_kept_until_cleared() {
+        let epoll = Epoll::new().unwrap();
+        let mut events = empty_events();
+
+        // Several wake ups before the loop gets to them are reported once
+        epoll.wake();
+        epoll.wake();
+        assert_eq!(epoll.wait(&mut events[..], 0).unwrap(), 1);
+        assert_eq!(epoll.wait(&mut events[..], 0).unwrap(), 1);
+
+        epoll.clear_wake();
+        assert_eq!(epoll.wait(&mut events[..], 0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::{fmt, io};
+use std::error::Error as StdError;
+
+
+/// Errors reported by hydrogen itself.
+///
+/// The reason a connection was removed is not reported through this type, it is passed as a
+/// `std::io::Error` to `Handler::on_connection_removed`.
+#[derive(Debug)]
+pub enum Error {
+    /// The passed `Config` is invalid, with a description of why.
+    InvalidConfig(String),
+    /// A listening socket could not be bound to its configured address, or the socket to be
+    /// adopted was not usable.
+    Bind(io::Error),
+    /// The epoll instance could not be created.
+    EpollCreate(io::Error),
+    /// `epoll_wait` failed for a reason other than `EINTR`.
+    EpollWait(io::Error),
+    /// One of the server's threads could not be spawned.
+    ThreadSpawn(io::Error),
+    /// The connection has been, or is about to be, removed, so nothing more can be sent on it.
+    ConnectionClosed,
+    /// The connection's outbound queue is over `Config::tx_high_watermark`, so nothing was sent.
+    /// `Handler::on_writable` is called once it has drained.
+    Backpressure,
+    /// Writing to the connection failed. The connection is now closed.
+    Io(io::Error),
+    /// A `Handler` callback, or a method of a connection's `Stream`, panicked, with the name of
+    /// the callback or method and the panic's message.
+    HandlerPanic(&'static str, String),
+    /// Sockets could not be handed off to, or taken over from, another process through
+    /// `Config::handoff_path`.
+    Handoff(io::Error),
+    /// An outbound connection could not be started through `ServerHandle::connect`.
+    Connect(io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidConfig(ref reason) => write!(f, "Invalid config: {}", reason),
+            Error::Bind(ref err) => write!(f, "Binding listener: {}", err),
+            Error::EpollCreate(ref err) => write!(f, "Creating epoll instance: {}", err),
+            Error::EpollWait(ref err) => write!(f, "During epoll_wait: {}", err),
+            Error::ThreadSpawn(ref err) => write!(f, "Spawning thread: {}", err),
+            Error::ConnectionClosed => write!(f, "Connection closed"),
+            Error::Backpressure => write!(f, "Outbound queue full"),
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::HandlerPanic(callback, ref msg) => {
+                write!(f, "Handler panicked in {}: {}", callback, msg)
+            }
+            Error::Handoff(ref err) => write!(f, "During handoff: {}", err),
+            Error::Connect(ref err) => write!(f, "Connecting: {}", err)
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::InvalidConfig(_)
+            | Error::ConnectionClosed
+            | Error::Backpressure
+            | Error::HandlerPanic(..) => None,
+            Error::Bind(ref err)
+            | Error::EpollCreate(ref err)
+            | Error::EpollWait(ref err)
+            | Error::ThreadSpawn(ref err)
+            | Error::Io(ref err)
+            | Error::Handoff(ref err)
+            | Error::Connect(ref err) => Some(err)
+        }
+    }
+}
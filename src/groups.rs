@@ -0,0 +1,281 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use threadpool::ThreadPool;
+
+use types::{ConnectionId, ConnectionShards, HydrogenSocket};
+use error::Error;
+
+
+// Smallest number of recipients worth handing to another thread during a broadcast
+const MIN_BROADCAST_CHUNK: usize = 64;
+
+/// Group membership, indexed in both directions so a removed connection can leave all of its
+/// groups without traversing every group.
+struct Membership {
+    members: HashMap<String, HashSet<ConnectionId>>,
+    groups: HashMap<ConnectionId, HashSet<String>>
+}
+
+/// Named sets of connections that buffers can be broadcast to.
+///
+/// Obtained through `ServerHandle::groups`. A connection leaves every group it belongs to when it
+/// is removed from the server.
+#[derive(Clone)]
+pub struct Groups {
+    membership: Arc<RwLock<Membership>>,
+    shards: ConnectionShards,
+    thread_pool: ThreadPool
+}
+
+/// Shared state of a single broadcast, worked through by the caller and the I/O threadpool.
+struct Broadcast {
+    buf: Vec<u8>,
+    chunks: Mutex<Vec<Vec<HydrogenSocket>>>,
+    failures: Mutex<Vec<(ConnectionId, Error)>>,
+    remaining: Mutex<usize>,
+    finished: Condvar
+}
+
+impl Groups {
+    pub fn new(shards: ConnectionShards, thread_pool: ThreadPool) -> Groups {
+        Groups {
+            membership: Arc::new(RwLock::new(Membership {
+                members: HashMap::new(),
+                groups: HashMap::new()
+            })),
+            shards,
+            thread_pool
+        }
+    }
+
+    /// Adds the connection with `id` to `group`, creating the group if needed.
+    ///
+    /// Returns false if there is no such connection.
+    pub fn join(&self, group: &str, id: ConnectionId) -> bool {
+        let mut membership = match self.membership.write() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        // Checked while holding the membership lock, connections are removed from the slab
+        // before they are removed from their groups, so nothing removed can be added here.
+        if self.shards.find(id).is_none() {
+            return false;
+        }
+
+        membership.members.entry(group.to_string()).or_default().insert(id);
+        membership.groups.entry(id).or_default().insert(group.to_string());
+
+        true
+    }
+
+    /// Removes the connection with `id` from `group`. Empty groups are dropped.
+    pub fn leave(&self, group: &str, id: ConnectionId) {
+        let mut membership = match self.membership.write() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        leave_group(&mut membership, group, id);
+
+        let now_ungrouped = match membership.groups.get_mut(&id) {
+            Some(groups) => {
+                groups.remove(group);
+                groups.is_empty()
+            }
+            None => false
+        };
+        if now_ungrouped {
+            membership.groups.remove(&id);
+        }
+    }
+
+    /// Returns the ids of every connection in `group`.
+    pub fn members(&self, group: &str) -> Vec<ConnectionId> {
+        let membership = match self.membership.read() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        match membership.members.get(group) {
+            Some(members) => members.iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Sends `buf` to every connection in `group`, and returns the connections it could not be
+    /// sent to along with the reason.
+    ///
+    /// Large groups are split across the I/O threadpool, with the calling thread working through
+    /// its share as well. This blocks until every recipient has been attempted, and is safe to
+    /// call from within `Handler` callbacks.
+    pub fn broadcast(&self, group: &str, buf: &[u8]) -> Vec<(ConnectionId, Error)> {
+        let sockets = self.member_sockets(group);
+        if sockets.is_empty() {
+            return Vec::new();
+        }
+
+        let threads = cmp::max(self.thread_pool.max_count(), 1);
+        let chunk_size = cmp::max(sockets.len() / threads + 1, MIN_BROADCAST_CHUNK);
+        let chunks: Vec<Vec<HydrogenSocket>> = sockets.chunks(chunk_size)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        let num_chunks = chunks.len();
+        trace!("Broadcasting to {} connections in {} chunks", sockets.len(), num_chunks);
+
+        let broadcast = Arc::new(Broadcast {
+            buf: buf.to_vec(),
+            chunks: Mutex::new(chunks),
+            failures: Mutex::new(Vec::new()),
+            remaining: Mutex::new(num_chunks),
+            finished: Condvar::new()
+        });
+
+        for _ in 1..num_chunks {
+            let broadcast = broadcast.clone();
+            self.thread_pool.execute(move || work_broadcast(&broadcast));
+        }
+
+        // Chunks are claimed from a shared list rather than assigned, so this never waits on a
+        // chunk that is only queued behind a busy threadpool, even from a threadpool thread.
+        work_broadcast(&broadcast);
+
+        let mut remaining = match broadcast.remaining.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        while *remaining > 0 {
+            remaining = match broadcast.finished.wait(remaining) {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+        }
+        drop(remaining);
+
+        let mut failures = match broadcast.failures.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        failures.drain(..).collect()
+    }
+
+    /// Removes the connection from every group it belongs to.
+    ///
+    /// Called by the event loop once the connection has been removed from the slab.
+    pub fn remove_connection(&self, id: ConnectionId) {
+        let mut membership = match self.membership.write() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        if let Some(groups) = membership.groups.remove(&id) {
+            for group in groups.iter() {
+                leave_group(&mut membership, group, id);
+            }
+        }
+    }
+
+    fn member_sockets(&self, group: &str) -> Vec<HydrogenSocket> {
+        let membership = match self.membership.read() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        let members = match membership.members.get(group) {
+            Some(members) => members,
+            None => return Vec::new()
+        };
+
+        // Each shard is read once, rather than once per member
+        let mut sockets = Vec::<HydrogenSocket>::with_capacity(members.len());
+        for slab in self.shards.iter() {
+            let connections = match slab.read() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            for id in members.iter() {
+                if let Some(arc_connection) = connections.find(*id) {
+                    sockets.push(HydrogenSocket::new(arc_connection.clone()));
+                }
+            }
+        }
+
+        sockets
+    }
+}
+
+fn leave_group(membership: &mut Membership, group: &str, id: ConnectionId) {
+    let now_empty = match membership.members.get_mut(group) {
+        Some(members) => {
+            members.remove(&id);
+            members.is_empty()
+        }
+        None => false
+    };
+    if now_empty {
+        membership.members.remove(group);
+    }
+}
+
+/// Claims and sends chunks of a broadcast until none are left unclaimed.
+fn work_broadcast(broadcast: &Broadcast) {
+    loop {
+        let chunk = { // Mutex lock
+            let mut chunks = match broadcast.chunks.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            match chunks.pop() {
+                Some(chunk) => chunk,
+                None => return
+            }
+        }; // Mutex unlock
+
+        // Counted as finished even if sending unwinds, so the broadcast is never left waiting
+        let _finished = ChunkFinished(broadcast);
+
+        let mut failures = Vec::<(ConnectionId, Error)>::new();
+        for socket in chunk.iter() {
+            if let Err(err) = socket.send(&broadcast.buf[..]) {
+                failures.push((socket.id(), err));
+            }
+        }
+
+        if !failures.is_empty() {
+            let mut all_failures = match broadcast.failures.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            all_failures.append(&mut failures);
+        }
+    }
+}
+
+/// Marks a claimed chunk of a broadcast as finished when dropped.
+struct ChunkFinished<'a>(&'a Broadcast);
+
+impl<'a> Drop for ChunkFinished<'a> {
+    fn drop(&mut self) {
+        let mut remaining = match self.0.remaining.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
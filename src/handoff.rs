@@ -0,0 +1,404 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::{mem, ptr};
+use std::path::Path;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, OwnedFd};
+
+use libc;
+
+use listener;
+use types::ListenerId;
+
+
+// A successor connects to the running server's handoff socket, and the running server writes
+// a header, one record per socket it hands off, then an end record. Each socket record carries
+// its fd with SCM_RIGHTS, attached to the record's first byte. Integers are little endian.
+//
+// header:     "HYDROGEN", u32 version
+// listener:   u8 1, u32 listener id
+// connection: u8 2, u32 listener id, u64 blob length, u64 queued length, blob, queued bytes
+// end:        u8 0
+const MAGIC: &[u8; 8] = b"HYDROGEN";
+const VERSION: u32 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_LISTENER: u8 = 1;
+const RECORD_CONNECTION: u8 = 2;
+
+// Largest blob and queued bytes a connection record may carry. Both are read into memory, so
+// lengths from the other process are checked against these before anything is allocated.
+pub const MAX_BLOB_LEN: usize = 16 << 20;
+pub const MAX_QUEUED_LEN: usize = 64 << 20;
+
+/// A connection handed off by the previous server.
+pub struct HandedOffConnection {
+    /// Listener the connection was accepted on
+    pub listener: ListenerId,
+    pub fd: OwnedFd,
+    /// Returned from the previous server's `Handler::on_connection_handoff`
+    pub blob: Vec<u8>,
+    /// Bytes the previous server had queued, but not yet written
+    pub queued: Vec<u8>
+}
+
+/// Everything handed off by the previous server.
+#[derive(Default)]
+pub struct HandedOff {
+    pub listeners: Vec<(ListenerId, OwnedFd)>,
+    pub connections: Vec<HandedOffConnection>
+}
+
+/// Writes sockets to a successor that has connected to the handoff socket.
+pub struct Sender {
+    stream: UnixStream
+}
+
+impl Sender {
+    pub fn new(mut stream: UnixStream) -> Result<Sender, Error> {
+        let mut header = Vec::<u8>::with_capacity(MAGIC.len() + 4);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        stream.write_all(&header[..])?;
+
+        Ok(Sender {
+            stream
+        })
+    }
+
+    pub fn send_listener(&mut self, id: ListenerId, fd: RawFd) -> Result<(), Error> {
+        let mut record = vec![RECORD_LISTENER];
+        record.extend_from_slice(&id.0.to_le_bytes());
+
+        send_with_fd(&mut self.stream, &record[..], fd)
+    }
+
+    pub fn send_connection(&mut self,
+                           listener: ListenerId,
+                           fd: RawFd,
+                           blob: &[u8],
+                           queued: &[u8])
+                           -> Result<(), Error>
+    {
+        let mut record = vec![RECORD_CONNECTION];
+        record.extend_from_slice(&listener.0.to_le_bytes());
+        record.extend_from_slice(&(blob.len() as u64).to_le_bytes());
+        record.extend_from_slice(&(queued.len() as u64).to_le_bytes());
+
+        send_with_fd(&mut self.stream, &record[..], fd)?;
+        self.stream.write_all(blob)?;
+        self.stream.write_all(queued)
+    }
+
+    /// Tells the successor everything has been sent.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.stream.write_all(&[RECORD_END])
+    }
+}
+
+/// Takes over the sockets of the server listening for a successor at `path`.
+///
+/// Returns nothing handed off if no server is listening there. Blocks until the running server
+/// has stopped, and handed off everything it is going to.
+pub fn receive(path: &Path) -> Result<HandedOff, Error> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(ref err) if err.kind() == ErrorKind::NotFound
+                     || err.kind() == ErrorKind::ConnectionRefused => {
+            info!("No running server to take over from at {}", path.display());
+            return Ok(HandedOff::default());
+        }
+        Err(err) => return Err(err)
+    };
+    check_peer(stream.as_raw_fd())?;
+
+    info!("Taking over from the running server at {}...", path.display());
+    receive_from(stream)
+}
+
+/// Reads everything the running server hands off through `stream`.
+fn receive_from(stream: UnixStream) -> Result<HandedOff, Error> {
+    let mut receiver = Receiver {
+        stream,
+        fds: VecDeque::new()
+    };
+
+    let mut header = [0u8; 12];
+    receiver.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8..] != VERSION.to_le_bytes() {
+        return Err(Error::new(ErrorKind::InvalidData, "Unrecognized handoff header"));
+    }
+
+    let mut handed_off = HandedOff::default();
+    loop {
+        let mut kind = [0u8; 1];
+        receiver.read_exact(&mut kind)?;
+        match kind[0] {
+            RECORD_END => break,
+            RECORD_LISTENER => {
+                let id = ListenerId(receiver.read_u32()?);
+                let fd = receiver.take_fd()?;
+                handed_off.listeners.push((id, fd));
+            }
+            RECORD_CONNECTION => {
+                let listener = ListenerId(receiver.read_u32()?);
+                let blob_len = receiver.read_u64()?;
+                let queued_len = receiver.read_u64()?;
+                let fd = receiver.take_fd()?;
+                if blob_len > MAX_BLOB_LEN as u64 || queued_len > MAX_QUEUED_LEN as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          "Handed off connection is too large"));
+                }
+                let blob_len = blob_len as usize;
+                let queued_len = queued_len as usize;
+
+                let mut blob = vec![0u8; blob_len];
+                receiver.read_exact(&mut blob[..])?;
+                let mut queued = vec![0u8; queued_len];
+                receiver.read_exact(&mut queued[..])?;
+
+                handed_off.connections.push(HandedOffConnection {
+                    listener,
+                    fd,
+                    blob,
+                    queued
+                });
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Unrecognized handoff record"))
+        }
+    }
+
+    info!("Took over {} listeners and {} connections",
+          handed_off.listeners.len(),
+          handed_off.connections.len());
+    Ok(handed_off)
+}
+
+/// Fails unless the process on the other end of the Unix socket `fd` runs as the same user as
+/// this one, or as root.
+pub fn check_peer(fd: RawFd) -> Result<(), Error> {
+    let uid = unsafe { libc::geteuid() };
+    match listener::read_peer_credentials(fd) {
+        Some(cred) if cred.uid == uid || cred.uid == 0 => Ok(()),
+        Some(cred) => {
+            Err(Error::new(ErrorKind::PermissionDenied,
+                           format!("Handoff peer is running as uid {}", cred.uid)))
+        }
+        None => Err(Error::new(ErrorKind::PermissionDenied, "Handoff peer is unknown"))
+    }
+}
+
+/// Writes `buf` to the stream, with `fd` attached to its first byte.
+fn send_with_fd(stream: &mut UnixStream, buf: &[u8], fd: RawFd) -> Result<(), Error> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len()
+    };
+
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        loop {
+            let result = libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL);
+            if result >= 0 {
+                break result as usize;
+            }
+
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    };
+
+    // The fd went out with the first byte, anything left is plain data
+    stream.write_all(&buf[sent..])
+}
+
+/// Reads records from the running server, collecting any fds that arrive with them.
+struct Receiver {
+    stream: UnixStream,
+    /// Fds received and not yet claimed by a record, oldest first
+    fds: VecDeque<OwnedFd>
+}
+
+impl Receiver {
+    /// Fills `buf`, never reading past its end, so fds are only ever received with, or before,
+    /// the record they belong to.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut read = 0usize;
+        while read < buf.len() {
+            let num_read = self.recv(&mut buf[read..])?;
+            if num_read == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Handoff ended early"));
+            }
+            read += num_read;
+        }
+
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn take_fd(&mut self) -> Result<OwnedFd, Error> {
+        match self.fds.pop_front() {
+            Some(fd) => Ok(fd),
+            None => Err(Error::new(ErrorKind::InvalidData, "Handoff record without an fd"))
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        // Room for more fds than are ever attached to one record, so none are truncated
+        const MAX_FDS: u32 = 4;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+
+        let space = unsafe {
+            libc::CMSG_SPACE(MAX_FDS * mem::size_of::<RawFd>() as u32)
+        } as usize;
+        let mut control = vec![0u8; space];
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        let num_read = loop {
+            let result = unsafe {
+                libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+            };
+            if result >= 0 {
+                break result as usize;
+            }
+
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        };
+
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for i in 0..(data_len / mem::size_of::<RawFd>()) {
+                        let fd = ptr::read_unaligned(data.add(i));
+                        self.fds.push_back(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Handoff fds were truncated"));
+        }
+
+        Ok(num_read)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn sockets_survive_a_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        let (a, b) = UnixStream::pair().unwrap();
+        check_peer(b.as_raw_fd()).unwrap();
+        let sender = thread::spawn(move || {
+            let mut sender = Sender::new(a).unwrap();
+            sender.send_listener(ListenerId(3), listener.as_raw_fd()).unwrap();
+            sender.send_connection(ListenerId(3), accepted.as_raw_fd(), b"session", b"pending")
+                .unwrap();
+            sender.finish().unwrap();
+        });
+        let handed_off = receive_from(b).unwrap();
+        sender.join().unwrap();
+
+        // The sender's fds are closed, the received ones are duplicates of them
+        assert_eq!(handed_off.listeners.len(), 1);
+        let (id, ref fd) = handed_off.listeners[0];
+        assert_eq!(id, ListenerId(3));
+        let listener = TcpListener::from(fd.try_clone().unwrap());
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let _second = TcpStream::connect(addr).unwrap();
+        listener.accept().unwrap();
+
+        assert_eq!(handed_off.connections.len(), 1);
+        let connection = &handed_off.connections[0];
+        assert_eq!(connection.listener, ListenerId(3));
+        assert_eq!(connection.blob, b"session");
+        assert_eq!(connection.queued, b"pending");
+        let mut stream = TcpStream::from(connection.fd.try_clone().unwrap());
+        stream.write_all(b"resumed").unwrap();
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"resumed");
+    }
+
+    #[test]
+    fn oversized_connection_record_is_rejected() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut sender = Sender::new(a).unwrap();
+        let fd = sender.stream.as_raw_fd();
+
+        let mut record = vec![RECORD_CONNECTION];
+        record.extend_from_slice(&7u32.to_le_bytes());
+        record.extend_from_slice(&u64::MAX.to_le_bytes());
+        record.extend_from_slice(&0u64.to_le_bytes());
+        send_with_fd(&mut sender.stream, &record[..], fd).unwrap();
+
+        match receive_from(b) {
+            Ok(_) => panic!("Oversized record accepted"),
+            Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData)
+        }
+    }
+}
@@ -22,6 +22,11 @@
 //! Interaction to `hydrogen::Stream` trait objects is made through a simple wrapper,
 //! `HydrogenSocket`, to ensure thread safety.
 //!
+//! # Clients
+//!
+//! [`hydrogen::client`][client] keeps outgoing connections open to a set of endpoints, on the
+//! same event loops and threadpool, reconnecting with an exponential backoff.
+//!
 //! # Example Usage
 //!
 //! The following is a simple snippet using the [simple-stream][simple-stream-repo] crate to
@@ -32,8 +37,13 @@
 //! extern crate hydrogen;
 //! extern crate simple_stream as ss;
 //!
+//! use std::io::Write;
+//! use std::borrow::Cow;
+//! use std::time::Duration;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
 //! use hydrogen;
-//! use hydrogen::{Stream as HydrogenStream, HydrogenSocket};
+//! use hydrogen::{Stream as HydrogenStream, HydrogenSocket, ConnectionId, ListenerId, ListenAddr};
 //! use ss::frame::Frame;
 //! use ss::frame::simple::{SimpleFrame, SimpleFrameBuilder};
 //! use ss::{Socket, Plain, NonBlocking, SocketOptions};
@@ -41,7 +51,8 @@
 //!
 //! #[derive(Clone)]
 //! pub struct Stream {
-//!     inner: Plain<Socket, SimpleFrameBuilder>
+//!     inner: Plain<Socket, SimpleFrameBuilder>,
+//!     socket: Socket
 //! }
 //!
 //! impl HydrogenStream for Stream {
@@ -58,9 +69,12 @@
 //!         }
 //!     }
 //!
-//!     fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
-//!         let frame = SimpleFrame::new(buf);
-//!         self.inner.nb_send(&frame)
+//!     fn encode<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+//!         Cow::Owned(SimpleFrame::new(buf).to_bytes())
+//!     }
+//!
+//!     fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
+//!         self.socket.write(buf)
 //!     }
 //!
 //!     fn shutdown(&mut self) -> Result<(), Error> {
@@ -72,33 +86,58 @@
 //! }
 //!
 //!
+//! #[derive(Default)]
+//! pub struct Session {
+//!     messages_received: AtomicUsize
+//! }
+//!
 //! struct Server;
 //! impl hydrogen::Handler for Server {
-//!     fn on_server_created(&mut self, fd: RawFd) {
+//!     type Context = Session;
+//!
+//!     fn on_server_created(&self, listener: ListenerId, fd: RawFd) {
 //!
 //!     }
 //!
-//!     fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<HydrogenStream>> {
+//!     fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+//!         -> (Box<dyn HydrogenStream>, Session)
+//!     {
 //!
 //!     }
 //!
-//!     fn on_data_received(&mut self, socket: HydrogenSocket, buffer: Vec<u8>) {
-//!
+//!     fn on_data_received(&self, socket: HydrogenSocket<Session>, buffer: Vec<u8>) {
+//!         socket.context().messages_received.fetch_add(1, Ordering::Relaxed);
 //!     }
 //!
-//!     fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
+//!     fn on_connection_removed(&self, id: ConnectionId, err: Error) {
 //!
 //!     }
 //! }
 //!
 //!
 //! fn main() {
-//!     hydrogen::begin(Server, hydrogen::Config {
-//!         addr: "0.0.0.0".to_string(),
-//!         port: 1337,
+//!     let server = hydrogen::begin(Box::new(Server), hydrogen::Config {
+//!         listeners: vec![hydrogen::ListenerConfig {
+//!             id: ListenerId(0),
+//!             addr: ListenAddr::Tcp {
+//!                 addr: "::".to_string(),
+//!                 port: 1337,
+//!                 v6_only: Some(false)
+//!             }
+//!         }],
 //!         max_threads: 8,
-//!         pre_allocated: 100000
-//!     });
+//!         reactors: 1,
+//!         pre_allocated: 100000,
+//!         tx_high_watermark: 1024 * 1024,
+//!         tx_low_watermark: 64 * 1024,
+//!         idle_timeout: Some(Duration::from_secs(300)),
+//!         first_byte_timeout: Some(Duration::from_secs(10)),
+//!         write_stall_timeout: Some(Duration::from_secs(30)),
+//!         handoff_path: None
+//!     }).unwrap();
+//!
+//!     // Runs until `server.shutdown()` is called from elsewhere
+//!     server.join();
 //! }
 //!
 //! ```
@@ -109,6 +148,7 @@
 //! [stream-trait]: https://nathansizemore.github.io/hydrogen/hydrogen/trait.Stream.html
 //! [trait-objects]: https://doc.rust-lang.org/book/trait-objects.html
 //! [handler]: https://nathansizemore.github.io/hydrogen/hydrogen/trait.Handler.html
+//! [client]: https://nathansizemore.github.io/hydrogen/hydrogen/client/index.html
 //! [simple-stream-repo]: https://github.com/nathansizemore/simple-stream
 
 
@@ -117,64 +157,320 @@ extern crate log;
 extern crate libc;
 extern crate errno;
 extern crate threadpool;
-extern crate simple_slab;
 
 
-use std::io::Error;
-use std::sync::Arc;
-use std::cell::UnsafeCell;
+use std::{cmp, io};
+use std::io::IoSlice;
+use std::borrow::Cow;
 use std::os::unix::io::{RawFd, AsRawFd};
 
 
-pub use config::Config;
-pub use types::HydrogenSocket;
+// Most buffers a single writev(2) accepts on Linux
+const IOV_MAX: usize = 1024;
 
+
+pub use config::{Config, ListenAddr, ListenerConfig};
+pub use error::Error;
+pub use groups::Groups;
+pub use timer::TimerHandle;
+pub use types::{ConnectionId, HydrogenSocket, ListenerId, PeerCredentials, SendStatus,
+                ServerHandle};
+
+mod tx;
+mod slab;
+mod listener;
+mod handoff;
+mod epoll;
+mod timer;
 mod types;
+mod error;
+mod groups;
 mod server;
 mod config;
 
+pub mod client;
+
 
 /// Trait object responsible for handling reported I/O events.
-pub trait Stream : AsRawFd + Send + Sync {
+///
+/// hydrogen owns the stream once it is returned from `Handler::on_new_connection`, and never
+/// calls into it from more than one thread at a time.
+pub trait Stream : AsRawFd + Send {
     /// Called when epoll reports data is available for read.
     ///
     /// This method should read until `ErrorKind::WouldBlock` is received. At that time, all
     /// complete messages should be returned, otherwise return the std::io::Error.
-    fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error>;
-    /// Called as the internal writer for the HydrogenSocket wrapper.
+    fn recv(&mut self) -> Result<Vec<Vec<u8>>, io::Error>;
+    /// Called by `HydrogenSocket::send` with the consumer's buffer, before it is written or
+    /// queued.
+    ///
+    /// This method should return the bytes to put on the wire for `buf`, for instance `buf`
+    /// prefixed by a frame header. The default sends `buf` unchanged.
+    fn encode<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Borrowed(buf)
+    }
+    /// Called as the internal writer for the HydrogenSocket wrapper and the connection's
+    /// outbound queue.
+    ///
+    /// This method should write as much of `buf` as the socket accepts without blocking, and
+    /// return the number of bytes written, or `ErrorKind::WouldBlock` if none could be. Anything
+    /// not written is kept and retried by hydrogen, it must not be buffered here.
+    fn send(&mut self, buf: &[u8]) -> Result<usize, io::Error>;
+    /// Called by `HydrogenSocket::send_vectored`, and when flushing several queued buffers at
+    /// once, with the buffers to write in order.
     ///
-    /// This method should write until all bytes have been written or any `std::io::Error` is
-    /// returned.
-    fn send(&mut self, buf: &[u8]) -> Result<(), Error>;
+    /// This method follows the same rules as `send`. The default writes only the first
+    /// non-empty buffer through `send`, so each buffer costs at least one call. Streams writing
+    /// straight to their fd should override it with `hydrogen::writev`, which hands every buffer
+    /// to the kernel in a single `writev(2)`.
+    fn send_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize, io::Error> {
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.send(buf),
+            None => Ok(0)
+        }
+    }
+    /// Called by `HydrogenSocket::send_file`, and when flushing a queued file, to write up to
+    /// `len` bytes of the file `fd`, starting at `offset`.
+    ///
+    /// This method follows the same rules as `send`. The default copies up to 16KB of the file
+    /// into memory with `pread(2)` and writes it through `send`, so it works for streams that
+    /// transform what they write, but is not zero-copy. Streams writing straight to their fd
+    /// should override it with `hydrogen::sendfile`, which has the kernel copy from the file to
+    /// the socket with `sendfile(2)`.
+    fn send_file(&mut self, fd: RawFd, offset: u64, len: usize) -> Result<usize, io::Error> {
+        let mut buf = [0u8; 16384];
+        let to_read = cmp::min(len, buf.len());
+        let num_read = unsafe {
+            libc::pread(fd, buf.as_mut_ptr() as *mut libc::c_void, to_read, offset as libc::off_t)
+        };
+        if num_read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if num_read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shorter than len"));
+        }
+
+        self.send(&buf[..num_read as usize])
+    }
     /// This method is called when any error, other than `ErrorKind::WouldBlock`, is returned from
     /// a `recv` or `send` call.
-    fn shutdown(&mut self) -> Result<(), Error>;
+    fn shutdown(&mut self) -> Result<(), io::Error>;
 }
 
 /// Events reported to lib consumer.
-pub trait Handler {
-    /// This method is called once the listening RawFd has been created.
+///
+/// Callbacks are made from the listener thread, the event loop, and the I/O threadpool, often
+/// at the same time, so any state the handler keeps must be safe to share between them.
+pub trait Handler : Send + Sync {
+    /// Per-connection state, created alongside the stream in `on_new_connection`, and reachable
+    /// from every `HydrogenSocket` for the connection through `HydrogenSocket::context`.
+    ///
+    /// Handlers without any per-connection state should use `()`.
+    type Context: Send + Sync + 'static;
+    /// This method is called once for each listening socket, when its RawFd has been created.
+    /// TCP listeners create one socket for each of `Config::reactors`.
     ///
-    /// It should be used to set/remove any flags on the underlying RawFd before `listen` is
-    /// called on the fd.
-    fn on_server_created(&mut self, fd: RawFd);
-    /// This method is called whenever `accept` returns a new TCP connection.
+    /// It should be used to set/remove any flags on the underlying RawFd before `bind` and
+    /// `listen` are called on the fd. For TCP listeners, `SO_REUSEADDR`, and `IPV6_V6ONLY` when
+    /// configured, have already been set. Sockets adopted through `ListenAddr::Fd` or
+    /// `ListenAddr::Systemd` are already bound and listening when this is called, once for each
+    /// fd adopted.
+    fn on_server_created(&self, listener: ListenerId, fd: RawFd);
+    /// This method is called whenever `accept` returns a new connection, on the listener
+    /// identified by `listener`.
     ///
-    /// The returned trait object is added to the connection pool and the epoll interest list.
-    fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<Stream>>;
+    /// The returned trait object is added to the connection pool and the epoll interest list,
+    /// and the returned context is kept with it until the connection has been removed.
+    /// `id` identifies the connection in every later event, and never refers to any other
+    /// connection, even after this one has been removed.
+    fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+        -> (Box<dyn Stream>, Self::Context);
     /// This method is called whenever the `recv` call returns an Ok(_) result.
-    fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>);
+    fn on_data_received(&self, socket: HydrogenSocket<Self::Context>, buf: Vec<u8>);
     /// This method is called after a stream has been removed from the connection poll and epoll
-    /// interest list, with the `std::io::Error` as the reason removed.
+    /// interest list, with the `std::io::Error` as the reason removed. Connections closed through
+    /// `HydrogenSocket::close` or `close_after_flush` receive the reason passed there.
+    ///
+    /// Connections removed because a callback panicked while handling them receive an
+    /// `ErrorKind::Other` error wrapping `Error::HandlerPanic`.
     ///
     /// At the time of this call, the underlying fd has been shutdown and closed. No system level
     /// shutdown is needed, only application level cleanup.
-    fn on_connection_removed(&mut self, fd: RawFd, err: Error);
+    fn on_connection_removed(&self, id: ConnectionId, err: io::Error);
+    /// This method is called when flushing a connection's buffered output fails, after
+    /// `HydrogenSocket::send` has already returned `SendStatus::Queued` for it.
+    ///
+    /// The connection is removed right after this call, and `on_connection_removed` follows.
+    #[allow(unused_variables)]
+    fn on_send_error(&self, id: ConnectionId, err: io::Error) { }
+    /// This method is called when a send fills a connection's outbound queue to
+    /// `Config::tx_high_watermark`, on the thread that made the send.
+    ///
+    /// Sends to the connection fail with `Error::Backpressure` until `on_writable` is called.
+    #[allow(unused_variables)]
+    fn on_backpressure(&self, id: ConnectionId) { }
+    /// This method is called once the outbound queue of a connection previously reported
+    /// through `on_backpressure` has drained to `Config::tx_low_watermark`.
+    #[allow(unused_variables)]
+    fn on_writable(&self, socket: HydrogenSocket<Self::Context>) { }
+    /// This method is called once a connect started through `ServerHandle::connect` has
+    /// finished, with a socket for the connection, or the reason it failed.
+    ///
+    /// A connection that connects is from then on treated exactly as an accepted one, and is
+    /// reported to `on_connection_removed` once removed. One that fails, including one closed,
+    /// timed out, or caught by a shutdown before it connected, is only ever reported here.
+    #[allow(unused_variables)]
+    fn on_connect_result(&self,
+                         id: ConnectionId,
+                         result: Result<HydrogenSocket<Self::Context>, io::Error>)
+    { }
+    /// This method is called when the server hits an error it is unable to recover from.
+    ///
+    /// The server begins shutting down immediately after this call, exactly as if
+    /// `ServerHandle::shutdown` had been called.
+    #[allow(unused_variables)]
+    fn on_server_error(&self, err: Error) { }
+    /// This method is called after any other callback, or a scheduled timer's callback, panics,
+    /// with `Error::HandlerPanic` describing the panic, and the connection the callback was made
+    /// for, if any.
+    ///
+    /// The panic never reaches the server's threads. The connection is removed, and
+    /// `on_connection_removed` follows. A panic in `on_server_created` fails `hydrogen::begin`
+    /// instead, and a panic in `on_new_connection` closes the accepted fd without the
+    /// connection ever being added. Panics in a connection's `Stream` are reported here too,
+    /// named after the method, such as "Stream::recv", and also remove the connection.
+    #[allow(unused_variables)]
+    fn on_handler_panic(&self, id: Option<ConnectionId>, err: Error) { }
+    /// This method is called for every connection when a successor process takes over through
+    /// `Config::handoff_path`, once the I/O threadpool has finished all other work.
+    ///
+    /// Returning Some hands the connection's fd to the successor, along with the returned
+    /// bytes, which are passed to its `on_connection_adopted`. They should hold whatever the
+    /// successor needs to resume the connection, such as buffered input or session state.
+    /// Anything still in the connection's outbound queue is sent by the successor. Returning
+    /// None, as by default, closes the connection as any other shutdown would. So does
+    /// returning more than 16MB, or having more than 64MB queued. Either way, the
+    /// connection is then removed and reported to `on_connection_removed`.
+    ///
+    /// Connections made through `ServerHandle::connect` are never handed off, and this is not
+    /// called for them.
+    #[allow(unused_variables)]
+    fn on_connection_handoff(&self, socket: HydrogenSocket<Self::Context>) -> Option<Vec<u8>> {
+        None
+    }
+    /// This method is called, while `hydrogen::begin` is taking over from a running server, for
+    /// every connection the running server handed off, with the bytes its
+    /// `on_connection_handoff` returned.
+    ///
+    /// Like `on_new_connection`, it returns the stream and context for the connection, which
+    /// is assigned a new `id`. Returning None, as by default, closes the fd.
+    #[allow(unused_variables)]
+    fn on_connection_adopted(&self,
+                             id: ConnectionId,
+                             listener: ListenerId,
+                             fd: RawFd,
+                             blob: Vec<u8>)
+                             -> Option<(Box<dyn Stream>, Self::Context)>
+    {
+        None
+    }
 }
 
 /// Starts the server with the passed configuration and handler.
-pub fn begin<T>(handler: Box<T>, cfg: Config)
-    where T: Handler + Send + Sync + 'static
+///
+/// The server runs on background threads. The returned `ServerHandle` is used to stop it, to
+/// block the calling thread for as long as it runs, or to reach connections from outside of a
+/// `Handler` callback. Any failure while starting, such as being
+/// unable to bind the listener, is returned here and nothing is left running.
+///
+/// Each call starts an independent server, with its own epoll instances, threads and
+/// connections, so a process may run any number of them at once.
+pub fn begin<T>(handler: Box<T>, cfg: Config) -> Result<ServerHandle<T::Context>, Error>
+    where T: Handler + 'static
 {
-    server::begin(handler, cfg);
+    server::begin(handler, cfg)
+}
+
+/// Writes `bufs`, in order, to `fd` with a single `writev(2)`, and returns the number of bytes
+/// written.
+///
+/// Intended for `Stream::send_vectored` implementations that write straight to their fd. At most
+/// `IOV_MAX` buffers are passed on, the rest are left for the next call, as with any other short
+/// write.
+pub fn writev(fd: RawFd, bufs: &[IoSlice]) -> Result<usize, io::Error> {
+    // IoSlice is guaranteed to be ABI compatible with iovec
+    let count = cmp::min(bufs.len(), IOV_MAX) as libc::c_int;
+    loop {
+        let result = unsafe { libc::writev(fd, bufs.as_ptr() as *const libc::iovec, count) };
+        if result >= 0 {
+            return Ok(result as usize);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Writes up to `len` bytes of the file `file_fd`, starting at `offset`, to the socket `fd` with
+/// `sendfile(2)`, and returns the number of bytes written.
+///
+/// Intended for `Stream::send_file` implementations that write straight to their fd. The file's
+/// contents are copied by the kernel, and never pass through userspace.
+pub fn sendfile(fd: RawFd, file_fd: RawFd, offset: u64, len: usize) -> Result<usize, io::Error> {
+    let mut offset = offset as libc::off_t;
+    loop {
+        let result = unsafe { libc::sendfile(fd, file_fd, &mut offset, len) };
+        if result > 0 || (result == 0 && len == 0) {
+            return Ok(result as usize);
+        }
+        if result == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shorter than len"));
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+    use std::io::{IoSlice, Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn writev_writes_every_buffer_in_order() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let bufs = [IoSlice::new(b"head"), IoSlice::new(b""), IoSlice::new(b"body")];
+
+        assert_eq!(super::writev(a.as_raw_fd(), &bufs).unwrap(), 8);
+        drop(a);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"headbody");
+    }
+
+    #[test]
+    fn sendfile_writes_file_range() {
+        let path = ::std::env::temp_dir().join(format!("hydrogen-sendfile-{}", process::id()));
+        fs::File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+        let file = fs::File::open(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        assert_eq!(super::sendfile(a.as_raw_fd(), file.as_raw_fd(), 2, 5).unwrap(), 5);
+        let err = super::sendfile(a.as_raw_fd(), file.as_raw_fd(), 10, 5).unwrap_err();
+        assert_eq!(err.kind(), ::std::io::ErrorKind::UnexpectedEof);
+
+        drop(a);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"23456");
+    }
 }
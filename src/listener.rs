@@ -0,0 +1,891 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::{env, fs, mem, process, ptr};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+
+use libc;
+
+use config::{ListenAddr, ListenerConfig};
+use error::Error as HydrogenError;
+use types::PeerCredentials;
+
+
+// First fd passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket owned by the server.
+///
+/// The fd is closed when the listener is dropped, along with the socket file of a filesystem
+/// Unix listener that hydrogen bound itself, unless the listener was handed off to another
+/// process.
+pub struct Listener {
+    fd: RawFd,
+    /// Eventfd written to by `interrupt`, polled alongside the listening socket by `accept`
+    wake_fd: RawFd,
+    /// Socket file created by bind, removed again on drop
+    socket_path: Option<PathBuf>,
+    /// Accepted sockets are Unix sockets, with peer credentials
+    is_unix: bool,
+    /// Set once the fd has been sent to a successor process, which now owns the socket file
+    handed_off: AtomicBool
+}
+
+impl Listener {
+    /// Creates, binds and starts listening on the sockets described by `cfg`, or adopts the
+    /// already listening sockets it names. A TCP listener binds one `SO_REUSEPORT` socket for
+    /// each of `reactors`, when there is more than one. Systemd sockets may also produce more
+    /// than one.
+    ///
+    /// `setup` is called with each socket, after hydrogen has set its own options. Sockets
+    /// created here are not yet bound. An error from `setup` fails immediately, whereas sockets
+    /// that fail to bind are closed and the next resolved address is tried.
+    pub fn open<F>(cfg: &ListenerConfig, reactors: usize, mut setup: F)
+        -> Result<Vec<Listener>, HydrogenError>
+        where F: FnMut(RawFd) -> Result<(), HydrogenError>
+    {
+        let result = match cfg.addr {
+            ListenAddr::Tcp { ref addr, port, v6_only } => {
+                info!("Creating TCP listener {} on {}:{}...", cfg.id, addr, port);
+                bind_tcp(addr, port, v6_only, reactors, &mut setup)
+            }
+            ListenAddr::Unix { ref path, mode } => {
+                info!("Creating Unix listener {} on {}...", cfg.id, path.display());
+                bind_unix(path, mode, &mut setup).map(|result| result.map(|l| vec![l]))
+            }
+            ListenAddr::UnixAbstract { ref name } => {
+                info!("Creating abstract Unix listener {}...", cfg.id);
+                bind_unix_abstract(name, &mut setup).map(|result| result.map(|l| vec![l]))
+            }
+            ListenAddr::Fd { fd } => {
+                info!("Adopting fd {} as listener {}...", fd, cfg.id);
+                adopt(fd, &mut setup).map(|result| result.map(|l| vec![l]))
+            }
+            ListenAddr::Systemd { ref name, .. } => {
+                info!("Adopting systemd socket {} as listener {}...", name, cfg.id);
+                adopt_systemd(name, &mut setup)
+            }
+        };
+
+        match result {
+            Ok(Ok(listeners)) => Ok(listeners),
+            Ok(Err(err)) => {
+                error!("Creating listener {}: {}", cfg.id, err);
+                Err(HydrogenError::Bind(err))
+            }
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Binds the Unix socket a running server listens on for a successor process to take over
+    /// from it. Only processes of the same user may connect.
+    pub fn open_handoff(path: &Path) -> Result<Listener, HydrogenError> {
+        info!("Creating handoff listener on {}...", path.display());
+        match bind_unix(path, Some(0o600), &mut |_| Ok(())) {
+            Ok(Ok(listener)) => Ok(listener),
+            Ok(Err(err)) => {
+                error!("Creating handoff listener: {}", err);
+                Err(HydrogenError::Handoff(err))
+            }
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Takes over `fd`, a listening socket handed off by the previous server for the listener
+    /// described by `cfg`. The socket file of a Unix listener is owned from here on, and
+    /// removed once this listener is dropped.
+    pub fn take_over<F>(cfg: &ListenerConfig, fd: OwnedFd, mut setup: F)
+        -> Result<Listener, HydrogenError>
+        where F: FnMut(RawFd) -> Result<(), HydrogenError>
+    {
+        info!("Taking over fd {} as listener {}...", fd.as_raw_fd(), cfg.id);
+        let fd = fd.into_raw_fd();
+        match adopt(fd, &mut setup) {
+            Ok(Ok(mut listener)) => {
+                if let ListenAddr::Unix { ref path, .. } = cfg.addr {
+                    listener.socket_path = Some(path.clone());
+                }
+                Ok(listener)
+            }
+            Ok(Err(err)) => {
+                unsafe { libc::close(fd); }
+                error!("Taking over listener {}: {}", cfg.id, err);
+                Err(HydrogenError::Handoff(err))
+            }
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Blocks until a connection is accepted, and returns its fd, or None once `interrupt` has
+    /// been called.
+    pub fn accept(&self) -> Result<Option<RawFd>, Error> {
+        loop {
+            let mut poll_fds = [
+                libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: self.wake_fd, events: libc::POLLIN, revents: 0 }
+            ];
+            if unsafe { libc::poll(poll_fds.as_mut_ptr(), 2, -1) } < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if poll_fds[1].revents != 0 {
+                return Ok(None);
+            }
+
+            let fd = unsafe {
+                libc::accept4(self.fd, ptr::null_mut(), ptr::null_mut(), libc::SOCK_CLOEXEC)
+            };
+            if fd >= 0 {
+                return Ok(Some(fd));
+            }
+
+            // The listening socket is non-blocking, and may be shared with other processes
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::ConnectionAborted => {}
+                _ => return Err(err)
+            }
+        }
+    }
+
+    /// Returns the credentials of the process on the other end of `fd`, a socket accepted
+    /// through this listener, or None if it is not a Unix socket.
+    pub fn peer_credentials(&self, fd: RawFd) -> Option<PeerCredentials> {
+        if !self.is_unix {
+            return None;
+        }
+
+        read_peer_credentials(fd)
+    }
+
+    /// Interrupts any thread blocked in `accept`, which returns None from here on.
+    ///
+    /// The socket itself is left untouched, as it may be handed off to another process.
+    pub fn interrupt(&self) {
+        let buf = 1u64;
+        let result = unsafe {
+            libc::write(self.wake_fd,
+                        &buf as *const u64 as *const libc::c_void,
+                        mem::size_of::<u64>())
+        };
+        if result < 0 {
+            let err = Error::last_os_error();
+            error!("Interrupting listener fd: {}    {}", self.fd, err);
+        }
+    }
+
+    /// Returns true if `SO_REUSEPORT` is set on the socket, so the kernel spreads connections
+    /// between it and other sockets bound to the same address.
+    pub fn reuses_port(&self) -> bool {
+        get_int_opt(self.fd, libc::SOL_SOCKET, libc::SO_REUSEPORT).ok() == Some(1)
+    }
+
+    /// Keeps the socket file of a Unix listener in place once this listener is dropped, as the
+    /// fd has been sent to a successor process.
+    pub fn mark_handed_off(&self) {
+        self.handed_off.store(true, Ordering::SeqCst);
+    }
+
+    /// Wraps `fd`, creating the eventfd used to interrupt `accept`. `fd` is closed on failure.
+    fn new(fd: RawFd, is_unix: bool) -> Result<Listener, Error> {
+        let wake_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake_fd < 0 {
+            let err = Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+
+        Ok(Listener {
+            fd,
+            wake_fd,
+            socket_path: None,
+            is_unix,
+            handed_off: AtomicBool::new(false)
+        })
+    }
+}
+
+/// Returns the credentials of the process on the other end of the Unix socket `fd`.
+pub fn read_peer_credentials(fd: RawFd) -> Option<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+    if result < 0 {
+        let err = Error::last_os_error();
+        error!("Reading SO_PEERCRED of fd: {}    {}", fd, err);
+        return None;
+    }
+
+    Some(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid
+    })
+}
+
+/// Returns true if `fd` is a Unix socket.
+pub fn is_unix_socket(fd: RawFd) -> bool {
+    get_int_opt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN).ok() == Some(libc::AF_UNIX)
+}
+
+/// Starts a non-blocking connect to `addr`, returning the socket while the connect is most
+/// likely still in progress. It has finished once the socket is reported writable.
+pub fn connect_tcp(addr: &SocketAddr) -> Result<RawFd, Error> {
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(family, flags, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let (storage, len) = socket_addr_to_raw(addr);
+    let result = unsafe {
+        libc::connect(fd,
+                      &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                      len)
+    };
+    if result < 0 {
+        // An interrupted connect carries on in the background, just as one in progress does
+        let err = Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) && err.kind() != ErrorKind::Interrupted {
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+    }
+
+    Ok(fd)
+}
+
+/// Returns the error a finished non-blocking connect on `fd` failed with, if it failed.
+pub fn connect_result(fd: RawFd) -> Result<(), Error> {
+    match get_int_opt(fd, libc::SOL_SOCKET, libc::SO_ERROR)? {
+        0 => Ok(()),
+        errno => Err(Error::from_raw_os_error(errno))
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::close(self.wake_fd);
+        }
+
+        if self.handed_off.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ref path) = self.socket_path {
+            if let Err(err) = fs::remove_file(path) {
+                error!("Removing socket file {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Binds `count` sockets to the first of the resolved addresses that succeeds, sharing it
+/// through `SO_REUSEPORT` when `count` is more than one.
+fn bind_tcp<F>(addr: &str, port: u16, v6_only: Option<bool>, count: usize, setup: &mut F)
+    -> Result<Result<Vec<Listener>, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let addrs = match (addr, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(err) => return Ok(Err(err))
+    };
+
+    let reuse_port = count > 1;
+    let mut last_err = Error::new(ErrorKind::InvalidInput, "Address resolved to nothing");
+    for mut addr in addrs {
+        // Any process of the same user could otherwise share the port, and its connections,
+        // without either noticing
+        if reuse_port && addr.port() != 0 {
+            if let Err(err) = check_unused(&addr, v6_only) {
+                last_err = err;
+                continue;
+            }
+        }
+
+        let first = match bind_tcp_socket(&addr, v6_only, reuse_port, setup)? {
+            Ok(listener) => listener,
+            Err(err) => {
+                last_err = err;
+                continue;
+            }
+        };
+
+        // A port of 0 is only picked by the first bind, the rest share whichever it got
+        match local_port(first.fd) {
+            Ok(port) => addr.set_port(port),
+            Err(err) => return Ok(Err(err))
+        }
+
+        let mut listeners = Vec::<Listener>::with_capacity(count);
+        listeners.push(first);
+        while listeners.len() < count {
+            match bind_tcp_socket(&addr, v6_only, reuse_port, setup)? {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => return Ok(Err(err))
+            }
+        }
+
+        if reuse_port {
+            info!("Listener bound to {} with {} sockets", addr, count);
+        } else {
+            info!("Listener bound to {}", addr);
+        }
+        return Ok(Ok(listeners));
+    }
+
+    Ok(Err(last_err))
+}
+
+fn bind_tcp_socket<F>(addr: &SocketAddr, v6_only: Option<bool>, reuse_port: bool, setup: &mut F)
+    -> Result<Result<Listener, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+    let listener = match new_listener(family) {
+        Ok(listener) => listener,
+        Err(err) => return Ok(Err(err))
+    };
+
+    if let Err(err) = set_bool_opt(listener.fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, true) {
+        return Ok(Err(err));
+    }
+    if reuse_port {
+        let result = set_bool_opt(listener.fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, true);
+        if let Err(err) = result {
+            return Ok(Err(err));
+        }
+    }
+    if let (&SocketAddr::V6(_), Some(v6_only)) = (addr, v6_only) {
+        let result = set_bool_opt(listener.fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6_only);
+        if let Err(err) = result {
+            return Ok(Err(err));
+        }
+    }
+
+    setup(listener.fd)?;
+
+    let (storage, len) = socket_addr_to_raw(addr);
+    match bind_and_listen(&listener, &storage, len, None) {
+        Ok(()) => Ok(Ok(listener)),
+        Err(err) => Ok(Err(err))
+    }
+}
+
+/// Fails if `addr` is already in use, by binding a socket without `SO_REUSEPORT` to it.
+fn check_unused(addr: &SocketAddr, v6_only: Option<bool>) -> Result<(), Error> {
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+    let probe = new_listener(family)?;
+
+    set_bool_opt(probe.fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, true)?;
+    if let (&SocketAddr::V6(_), Some(v6_only)) = (addr, v6_only) {
+        set_bool_opt(probe.fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6_only)?;
+    }
+
+    let (storage, len) = socket_addr_to_raw(addr);
+    let result = unsafe {
+        libc::bind(probe.fd,
+                   &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                   len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns the port a TCP socket is bound to.
+fn local_port(fd: RawFd) -> Result<u16, Error> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(fd,
+                          &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let port = match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let sin = unsafe {
+                &*(&storage as *const libc::sockaddr_storage as *const libc::sockaddr_in)
+            };
+            sin.sin_port
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe {
+                &*(&storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+            };
+            sin6.sin6_port
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "Not a TCP socket"))
+    };
+
+    Ok(u16::from_be(port))
+}
+
+/// Binds to a socket file at `path`, replacing a stale one left behind by an earlier process.
+fn bind_unix<F>(path: &Path, mode: Option<u32>, setup: &mut F)
+    -> Result<Result<Listener, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let (storage, len) = match unix_path_to_raw(path.as_os_str().as_bytes(), false) {
+        Ok(raw) => raw,
+        Err(err) => return Ok(Err(err))
+    };
+
+    if let Err(err) = remove_stale_socket(path) {
+        return Ok(Err(err));
+    }
+
+    let mut listener = match new_listener(libc::AF_UNIX) {
+        Ok(listener) => listener,
+        Err(err) => return Ok(Err(err))
+    };
+
+    setup(listener.fd)?;
+
+    if let Err(err) = bind_and_listen(&listener, &storage, len, Some((path, mode))) {
+        return Ok(Err(err));
+    }
+    listener.socket_path = Some(path.to_path_buf());
+
+    info!("Listener bound to {}", path.display());
+    Ok(Ok(listener))
+}
+
+/// Binds to `name` in the abstract namespace, which has no socket file to manage.
+fn bind_unix_abstract<F>(name: &[u8], setup: &mut F)
+    -> Result<Result<Listener, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let (storage, len) = match unix_path_to_raw(name, true) {
+        Ok(raw) => raw,
+        Err(err) => return Ok(Err(err))
+    };
+
+    let listener = match new_listener(libc::AF_UNIX) {
+        Ok(listener) => listener,
+        Err(err) => return Ok(Err(err))
+    };
+
+    setup(listener.fd)?;
+
+    if let Err(err) = bind_and_listen(&listener, &storage, len, None) {
+        return Ok(Err(err));
+    }
+
+    info!("Listener bound to abstract name {}", String::from_utf8_lossy(name));
+    Ok(Ok(listener))
+}
+
+/// Takes ownership of `fd`, once it has been checked to be a listening stream socket. An fd
+/// that is not one is left open.
+fn adopt<F>(fd: RawFd, setup: &mut F) -> Result<Result<Listener, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let family = match listening_family(fd) {
+        Ok(family) => family,
+        Err(err) => return Ok(Err(err))
+    };
+
+    // Owned from here, so the fd is closed on any failure
+    let listener = match Listener::new(fd, family == libc::AF_UNIX) {
+        Ok(listener) => listener,
+        Err(err) => return Ok(Err(err))
+    };
+
+    // Accepted fds must not leak into child processes
+    if let Err(err) = set_nonblocking_cloexec(fd) {
+        return Ok(Err(err));
+    }
+
+    setup(fd)?;
+
+    info!("Adopted listening fd {}", fd);
+    Ok(Ok(listener))
+}
+
+/// Adopts every socket systemd passed with the name `name`.
+fn adopt_systemd<F>(name: &str, setup: &mut F)
+    -> Result<Result<Vec<Listener>, Error>, HydrogenError>
+    where F: FnMut(RawFd) -> Result<(), HydrogenError>
+{
+    let fds = match systemd_fds(name) {
+        Ok(fds) => fds,
+        Err(err) => return Ok(Err(err))
+    };
+
+    let mut listeners = Vec::<Listener>::with_capacity(fds.len());
+    for fd in fds {
+        match adopt(fd, setup)? {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => return Ok(Err(err))
+        }
+    }
+
+    Ok(Ok(listeners))
+}
+
+/// Returns the fds passed through systemd socket activation with the name `name`, following
+/// `sd_listen_fds_with_names(3)`. Sockets without a name in `LISTEN_FDNAMES` are named
+/// "unknown", as they are by systemd.
+fn systemd_fds(name: &str) -> Result<Vec<RawFd>, Error> {
+    let not_passed = || {
+        Error::new(ErrorKind::NotFound, format!("No socket named {} was passed by systemd", name))
+    };
+
+    // Sockets passed to a parent process are not ours to take
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Err(not_passed())
+    };
+    if pid.trim().parse::<u32>().ok() != Some(process::id()) {
+        return Err(not_passed());
+    }
+
+    let num_fds = match env::var("LISTEN_FDS").map(|num_fds| num_fds.trim().parse::<RawFd>()) {
+        Ok(Ok(num_fds)) if num_fds >= 0 => num_fds,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "LISTEN_FDS is missing or invalid"))
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let mut fds = Vec::<RawFd>::new();
+    for i in 0..num_fds {
+        let fd_name = match names.next() {
+            Some(fd_name) if !fd_name.is_empty() => fd_name,
+            _ => "unknown"
+        };
+        if fd_name == name {
+            fds.push(SD_LISTEN_FDS_START + i);
+        }
+    }
+
+    if fds.is_empty() {
+        return Err(not_passed());
+    }
+
+    Ok(fds)
+}
+
+/// Removes the variables systemd passes sockets through from the environment, once they have
+/// been adopted, as `sd_listen_fds(3)` does when asked to. Child processes then never see
+/// variables describing sockets that are not theirs.
+///
+/// The environment is shared by the whole process, and is not safe to change while another
+/// thread may be reading it. This must only be called before any other thread is started.
+pub fn unset_systemd_env() {
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDNAMES");
+}
+
+/// Returns the address family of `fd`, or an error if it is not a listening stream socket.
+fn listening_family(fd: RawFd) -> Result<i32, Error> {
+    let accepting = get_int_opt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)?;
+    let sock_type = get_int_opt(fd, libc::SOL_SOCKET, libc::SO_TYPE)?;
+    if accepting == 0 || sock_type != libc::SOCK_STREAM {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("fd {} is not a listening stream socket", fd)));
+    }
+
+    get_int_opt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)
+}
+
+fn set_nonblocking_cloexec(fd: RawFd) -> Result<(), Error> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Creates an unbound stream socket, owned by the returned listener so it is closed on any
+/// failure.
+fn new_listener(family: i32) -> Result<Listener, Error> {
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(family, flags, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Listener::new(fd, family == libc::AF_UNIX)
+}
+
+/// Binds the listener to `storage`, then starts listening. A socket file created by the bind
+/// has `mode` applied before any connection can be accepted, and is removed again on failure.
+fn bind_and_listen(listener: &Listener,
+                   storage: &libc::sockaddr_storage,
+                   len: libc::socklen_t,
+                   socket_file: Option<(&Path, Option<u32>)>)
+                   -> Result<(), Error>
+{
+    let result = unsafe {
+        libc::bind(listener.fd,
+                   storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                   len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let result = match socket_file {
+        Some((path, Some(mode))) => set_mode(path, mode),
+        _ => Ok(())
+    };
+    let result = result.and_then(|_| {
+        if unsafe { libc::listen(listener.fd, libc::SOMAXCONN) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    });
+
+    if result.is_err() {
+        if let Some((path, _)) = socket_file {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    result
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Path contains a nul byte"))
+    };
+
+    if unsafe { libc::chmod(c_path.as_ptr(), mode as libc::mode_t) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Removes the socket file at `path` if nothing is listening on it any more. Anything else at
+/// `path`, or a socket still in use, is left alone for bind to fail on.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err)
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket file {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err)
+    }
+}
+
+fn set_bool_opt(fd: RawFd, level: i32, name: i32, value: bool) -> Result<(), Error> {
+    let value = value as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn get_int_opt(fd: RawFd, level: i32, name: i32) -> Result<i32, Error> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd,
+                         level,
+                         name,
+                         &mut value as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+fn socket_addr_to_raw(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref v4) => {
+            let sin = unsafe {
+                &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in)
+            };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from(*v4.ip()).to_be() };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref v6) => {
+            let sin6 = unsafe {
+                &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6)
+            };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: v6.ip().octets() };
+            sin6.sin6_scope_id = v6.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Builds a `sockaddr_un` for a filesystem path, or for a name in the abstract namespace.
+fn unix_path_to_raw(path: &[u8], is_abstract: bool)
+    -> Result<(libc::sockaddr_storage, libc::socklen_t), Error>
+{
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let sun = unsafe {
+        &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_un)
+    };
+    sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // Abstract names start with a nul byte, paths end with one
+    let offset = if is_abstract { 1 } else { 0 };
+    if path.is_empty() || path.len() + 1 > sun.sun_path.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Unix socket path is empty or too long"));
+    }
+    if !is_abstract && path.contains(&0) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Unix socket path contains a nul byte"));
+    }
+    for (i, byte) in path.iter().enumerate() {
+        sun.sun_path[i + offset] = *byte as libc::c_char;
+    }
+
+    let path_offset = mem::size_of::<libc::sa_family_t>();
+    let len = if is_abstract {
+        path_offset + 1 + path.len()
+    } else {
+        path_offset + path.len() + 1
+    };
+
+    Ok((storage, len as libc::socklen_t))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::FromRawFd;
+
+    use types::ListenerId;
+    use super::*;
+
+    fn fd_listener(fd: RawFd) -> ListenerConfig {
+        ListenerConfig {
+            id: ListenerId(0),
+            addr: ListenAddr::Fd { fd }
+        }
+    }
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+    }
+
+    #[test]
+    fn listening_fd_is_adopted() {
+        let fd = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let port = local_port(fd).unwrap();
+
+        let mut set_up = Vec::<RawFd>::new();
+        let listeners = Listener::open(&fd_listener(fd), 2, |fd| {
+            set_up.push(fd);
+            Ok(())
+        }).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].as_raw_fd(), fd);
+        assert_eq!(set_up, vec![fd]);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+
+        let _stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let accepted = listeners[0].accept().unwrap().unwrap();
+        assert!(listeners[0].peer_credentials(accepted).is_none());
+        unsafe { libc::close(accepted); }
+    }
+
+    #[test]
+    fn non_listening_fd_is_rejected_and_left_open() {
+        // Bound, but never listened on
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(fd >= 0);
+        let (storage, len) = socket_addr_to_raw(&"127.0.0.1:0".parse().unwrap());
+        let storage_ptr = &storage as *const libc::sockaddr_storage as *const libc::sockaddr;
+        assert_eq!(unsafe { libc::bind(fd, storage_ptr, len) }, 0);
+
+        let result = Listener::open(&fd_listener(fd), 1, |_| panic!("Set up a rejected fd"));
+        match result {
+            Err(HydrogenError::Bind(err)) => assert_eq!(err.kind(), ErrorKind::InvalidInput),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Adopted a socket that is not listening")
+        }
+        assert!(is_open(fd));
+
+        // Closes the fd
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+}
@@ -6,584 +6,2551 @@
 // http://mozilla.org/MPL/2.0/.
 
 
-use std::{mem, thread};
-use std::error::Error as StdError;
+use std::{cmp, panic, thread};
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
 use std::io::{Error, ErrorKind};
-use std::time::Duration;
-use std::cell::UnsafeCell;
-use std::sync::{Arc, Mutex};
-use std::net::{TcpStream, TcpListener};
-use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
+use std::thread::JoinHandle;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::os::unix::net::UnixStream;
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
 
 use libc;
 use errno::errno;
 use threadpool::ThreadPool;
-use simple_slab::Slab;
 
 use types::*;
-use config::Config;
-use super::{Stream, Handler};
+use tx::{TxState, TxLimits};
+use slab::Token;
+use listener;
+use listener::Listener;
+use handoff;
+use handoff::{HandedOff, HandedOffConnection, Sender};
+use epoll::{Epoll, DEFAULT_EVENTS, WAKE_TOKEN};
+use timer::{TimerWheel, TimerEvent, Scheduler, ScheduledTimer};
+use config::{Config, ListenAddr};
+use groups::Groups;
+use error::Error as HydrogenError;
+use super::{Handler, Stream};
 
 
-// When added to epoll, these will be the conditions of kernel notification:
-//
-// EPOLLIN          - Data is available in kernel buffer.
-// EPOLLRDHUP       - Peer closed connection.
-// EPOLLET          - Register in EdgeTrigger mode.
-// EPOLLONESHOT     - After an event is pulled out with epoll_wait(2) the associated
-//                    file descriptor is internally disabled and no other events will
-//                    be reported by the epoll interface.
-const DEFAULT_EVENTS: i32 = libc::EPOLLIN |
-                            libc::EPOLLRDHUP |
-                            libc::EPOLLET |
-                            libc::EPOLLONESHOT;
-
 // Maximum number of events returned from epoll_wait
-const MAX_EVENTS: i32 = 100;
+const MAX_EVENTS: usize = 100;
 
-// Useful to keep from passing a copy of a RawFd everywhere
-static mut epfd: RawFd = 0 as RawFd;
+// Source of ConnectionIds, which are never reused
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 
-pub fn begin(handler: Box<Handler>, cfg: Config) {
+pub fn begin<T>(handler: Box<T>, cfg: Config) -> Result<ServerHandle<T::Context>, HydrogenError>
+    where T: Handler + 'static
+{
     info!("Starting server...");
+    cfg.validate()?;
 
-    // Wrap handler in something we can share between threads
-    let event_handler = EventHandler(Box::into_raw(handler));
+    // Share the handler between threads
+    let handler: Box<dyn ErasedHandler> = handler;
+    let event_handler: EventHandler = Arc::from(handler);
 
-    // Create our new connections slab
-    let new_connection_slab = Arc::new(Mutex::new(Slab::<Connection>::new(10)));
+    start(cfg, event_handler)
+}
 
-    // Create our connection slab
-    let mut_slab = MutSlab {
-        inner: UnsafeCell::new(Slab::<Arc<Connection>>::new(cfg.pre_allocated))
+/// Creates the server's resources and threads. If any step fails, everything created by the
+/// previous steps is torn down before returning.
+pub fn start<C: 'static>(cfg: Config, event_handler: EventHandler)
+    -> Result<ServerHandle<C>, HydrogenError>
+{
+    // A running server hands over its listening sockets, which are used in place of binding
+    let mut handed_off = match cfg.handoff_path {
+        Some(ref path) => match handoff::receive(path) {
+            Ok(handed_off) => handed_off,
+            Err(err) => {
+                error!("Taking over from running server: {}", err);
+                return Err(HydrogenError::Handoff(err));
+            }
+        },
+        None => HandedOff::default()
     };
-    let connection_slab = Arc::new(mut_slab);
-
-    // Start the event loop
-    let threads = cfg.max_threads;
-    let eh_clone = event_handler.clone();
-    let new_connections = new_connection_slab.clone();
-    unsafe {
-        thread::Builder::new()
-            .name("Event Loop".to_string())
-            .spawn(move || {
-                event_loop(new_connections, connection_slab, eh_clone, threads)
-            })
-            .unwrap();
-    }
 
-    // Start the TcpListener loop
-    let eh_clone = event_handler.clone();
-    let listener_thread = unsafe {
-        thread::Builder::new()
-            .name("TcpListener Loop".to_string())
-            .spawn(move || { listener_loop(cfg, new_connection_slab, eh_clone) })
-            .unwrap()
+    // Bind up front, so the handle is able to interrupt the accept loops
+    let listeners = create_listeners(&cfg, &event_handler, &mut handed_off.listeners);
+    if cfg.listeners.iter().any(|l| matches!(l.addr, ListenAddr::Systemd { unset_env: true, .. })) {
+        listener::unset_systemd_env();
+    }
+    let listeners = listeners?;
+    let handoff_listener = match cfg.handoff_path {
+        Some(ref path) => Some(Arc::new(Listener::open_handoff(path)?)),
+        None => None
     };
-    let _ = listener_thread.join();
-}
 
-unsafe fn listener_loop(cfg: Config, new_connections: NewConnectionSlab, handler: EventHandler) {
-    info!("Starting incoming TCP connection listener...");
-    let listener_result = TcpListener::bind((&cfg.addr[..], cfg.port));
-    if listener_result.is_err() {
-        let err = listener_result.unwrap_err();
-        error!("Creating TcpListener: {}", err);
-        panic!();
+    info!("Creating {} epoll instances...", cfg.reactors);
+    let mut reactors = Vec::<Reactor>::with_capacity(cfg.reactors);
+    for _ in 0..cfg.reactors {
+        let epoll = match Epoll::new() {
+            Ok(epoll) => Arc::new(epoll),
+            Err(err) => {
+                error!("Creating epoll instance: {}", err);
+                return Err(HydrogenError::EpollCreate(err));
+            }
+        };
+        info!("Epoll instance created with fd: {}", epoll.as_raw_fd());
+
+        let capacity = cmp::max(cfg.pre_allocated / cfg.reactors, 1);
+        reactors.push(Reactor {
+            new_connections: Arc::new(Mutex::new(Vec::<Connection>::with_capacity(10))),
+            connection_slab: Arc::new(RwLock::new(Connections::new(capacity))),
+            scheduler: Scheduler::new(epoll.clone()),
+            epoll
+        });
+    }
+    let reactors = Arc::new(reactors);
+
+    let shutdown = Arc::new(Shutdown::new(
+        listeners.iter().map(|&(id, ref listener)| (id, Arc::downgrade(listener))).collect(),
+        handoff_listener.as_ref().map(Arc::downgrade),
+        reactors.iter().map(|reactor| reactor.epoll.clone()).collect()));
+
+    let shards = ConnectionShards::new(
+        reactors.iter().map(|reactor| reactor.connection_slab.clone()).collect());
+
+    info!("Creating I/O threadpool with {} threads", cfg.max_threads);
+
+    // ThreadPool with user specified number of threads, shared by every reactor
+    let thread_pool = ThreadPool::new(cfg.max_threads);
+
+    let groups = Groups::new(shards.clone(), thread_pool.clone());
+
+    let mut threads = Vec::<JoinHandle<()>>::with_capacity(listeners.len() + cfg.reactors);
+
+    // Start a TcpListener loop for each listening socket
+    let mut acceptor = Acceptor {
+        reactors: reactors.clone(),
+        pinned: None,
+        next_reactor: Arc::new(AtomicUsize::new(0)),
+        handler: event_handler.clone(),
+        tx_limits: TxLimits {
+            high_watermark: cfg.tx_high_watermark,
+            low_watermark: cfg.tx_low_watermark
+        },
+        timeouts: Timeouts {
+            idle: cfg.idle_timeout,
+            first_byte: cfg.first_byte_timeout,
+            write_stall: cfg.write_stall_timeout
+        }
+    };
+    for connection in handed_off.connections {
+        adopt_connection(connection, &acceptor);
+    }
+    let connector = acceptor.clone();
+    for (listener_id, pinned, listener) in assign_reactors(listeners, cfg.reactors) {
+        acceptor.pinned = pinned;
+        let acceptor = acceptor.clone();
+        let shutdown_clone = shutdown.clone();
+        let spawn_result = thread::Builder::new()
+            .name(format!("Listener Loop {}", listener_id))
+            .spawn(move || listener_loop(listener_id, listener, acceptor, shutdown_clone));
+        match spawn_result {
+            Ok(thread) => threads.push(thread),
+            Err(err) => return Err(abort_start(&shutdown, threads, err))
+        };
+    }
+    if let Some(listener) = handoff_listener {
+        let shutdown_clone = shutdown.clone();
+        let spawn_result = thread::Builder::new()
+            .name("Handoff Listener".to_string())
+            .spawn(move || handoff_loop(listener, shutdown_clone));
+        match spawn_result {
+            Ok(thread) => threads.push(thread),
+            Err(err) => return Err(abort_start(&shutdown, threads, err))
+        };
     }
 
-    let listener = listener_result.unwrap();
-    setup_listener_options(&listener, handler.clone());
-
-    info!("Incoming TCP conecction listener started");
+    let context = EventLoop {
+        reactors: reactors.clone(),
+        groups: groups.clone(),
+        handler: event_handler,
+        thread_pool,
+        shutdown: shutdown.clone()
+    };
 
-    for accept_attempt in listener.incoming() {
-        match accept_attempt {
-            Ok(tcp_stream) => handle_new_connection(tcp_stream, &new_connections, handler.clone()),
-            Err(e) => error!("Accepting connection: {}", e)
+    // Every reactor but the first only runs its event loop
+    for index in 1..cfg.reactors {
+        let context = context.clone();
+        let spawn_result = thread::Builder::new()
+            .name(format!("Event Loop {}", index))
+            .spawn(move || event_loop(context, index, Vec::new()));
+        match spawn_result {
+            Ok(thread) => threads.push(thread),
+            Err(err) => return Err(abort_start(&shutdown, threads, err))
         };
     }
 
-    debug!("Dropping TcpListener");
+    // Start the first reactor's event loop, it takes ownership of everything else from here.
+    // The other threads are handed over once it has spawned, so they can still be joined if
+    // spawning fails.
+    let (tx, rx) = mpsc::channel::<Vec<JoinHandle<()>>>();
+    let spawn_result = thread::Builder::new()
+        .name("Event Loop 0".to_string())
+        .spawn(move || {
+            let threads = match rx.recv() {
+                Ok(threads) => threads,
+                Err(_) => return
+            };
+            event_loop(context, 0, threads)
+        });
+    let event_loop_thread = match spawn_result {
+        Ok(thread) => thread,
+        Err(err) => return Err(abort_start(&shutdown, threads, err))
+    };
+    let _ = tx.send(threads);
 
-    drop(listener);
+    let scheduler = reactors[0].scheduler.clone();
+    Ok(ServerHandle::new(shutdown, shards, groups, scheduler, connector, event_loop_thread))
 }
 
-unsafe fn setup_listener_options(listener: &TcpListener, handler: EventHandler) {
-    info!("Setting up listener options");
-    let fd = listener.as_raw_fd();
-    let EventHandler(handler_ptr) = handler;
+/// Stops and joins any threads started before a thread failed to spawn.
+fn abort_start(shutdown: &Shutdown,
+               threads: Vec<JoinHandle<()>>,
+               err: Error)
+               -> HydrogenError
+{
+    error!("Spawning thread: {}", err);
+
+    shutdown.trigger();
+    for thread in threads {
+        let _ = thread.join();
+    }
 
-    (*handler_ptr).on_server_created(fd);
+    HydrogenError::ThreadSpawn(err)
 }
 
-unsafe fn handle_new_connection(tcp_stream: TcpStream,
-                                new_connections: &NewConnectionSlab,
-                                handler: EventHandler)
+/// Binds, or adopts, every configured listener, taking over the sockets in `handed_off` in place
+/// of binding listeners with the same id. If any fails, those already created are closed.
+fn create_listeners(cfg: &Config,
+                    handler: &EventHandler,
+                    handed_off: &mut Vec<(ListenerId, OwnedFd)>)
+                    -> Result<Vec<(ListenerId, Arc<Listener>)>, HydrogenError>
 {
-    debug!("New connection received");
-    // Take ownership of tcp_stream's underlying file descriptor
-    let fd = tcp_stream.into_raw_fd();
+    let mut listeners = Vec::<(ListenerId, Arc<Listener>)>::with_capacity(cfg.listeners.len());
+    for listener_cfg in cfg.listeners.iter() {
+        let setup = |fd| setup_listener_options(listener_cfg.id, fd, handler);
+
+        let mut taken_over = false;
+        while let Some(i) = handed_off.iter().position(|&(id, _)| id == listener_cfg.id) {
+            let (_, fd) = handed_off.remove(i);
+            let listener = Listener::take_over(listener_cfg, fd, setup)?;
+            listeners.push((listener_cfg.id, Arc::new(listener)));
+            taken_over = true;
+        }
+        if taken_over {
+            continue;
+        }
 
-    // Execute EventHandler's constructor
-    let EventHandler(handler_ptr) = handler;
-    let arc_stream = (*handler_ptr).on_new_connection(fd);
-
-    // Create a connection structure
-    let connection = Connection {
-        fd: fd,
-        err_mutex: Mutex::new(None),
-        tx_mutex: Mutex::new(()),
-        stream: arc_stream
-    };
+        for listener in Listener::open(listener_cfg, cfg.reactors, setup)? {
+            listeners.push((listener_cfg.id, Arc::new(listener)));
+        }
+    }
 
-    // Insert it into the NewConnectionSlab
-    let mut slab = match (*new_connections).lock() {
-        Ok(g) => g,
-        Err(p) => p.into_inner()
-    };
+    // Dropping the rest closes them, the running server has already stopped accepting on them
+    for &(id, _) in handed_off.iter() {
+        info!("Closing handed off listener {}, it is no longer configured", id);
+    }
 
-    (&mut *slab).insert(connection);
+    Ok(listeners)
 }
 
-/// Main event loop
-unsafe fn event_loop(new_connections: NewConnectionSlab,
-                     connection_slab: ConnectionSlab,
-                     handler: EventHandler,
-                     threads: usize)
+/// Pairs each listening socket with the reactor it feeds, if it feeds only one.
+///
+/// A listener with a `SO_REUSEPORT` socket for every reactor has each of them feed its own
+/// reactor, as the kernel already spreads connections between the sockets. Connections from
+/// any other listener are spread across every reactor in turn.
+fn assign_reactors(listeners: Vec<(ListenerId, Arc<Listener>)>, num_reactors: usize)
+    -> Vec<(ListenerId, Option<usize>, Arc<Listener>)>
 {
-    info!("Event loop starting...");
-    const MAX_WAIT: i32 = 1000; // Milliseconds
-
-    info!("Creating epoll instance...");
-    // Attempt to create an epoll instance
-    let result = libc::epoll_create(1);
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("Creating epoll instance: {}", err);
-        panic!();
+    let mut sockets = HashMap::<ListenerId, (usize, bool)>::new();
+    for &(id, ref listener) in listeners.iter() {
+        let entry = sockets.entry(id).or_insert((0, true));
+        entry.0 += 1;
+        entry.1 &= listener.reuses_port();
     }
 
-    // Epoll instance
-    epfd = result;
-    info!("Epoll instance created with fd: {}", epfd);
-
-    info!("Creating I/O threadpool with {} threads", threads);
+    let mut next = HashMap::<ListenerId, usize>::new();
+    let mut assigned = Vec::with_capacity(listeners.len());
+    for (id, listener) in listeners {
+        let pinned = match sockets[&id] {
+            (count, true) if num_reactors > 1 && count == num_reactors => {
+                let index = next.entry(id).or_insert(0);
+                *index += 1;
+                Some(*index - 1)
+            }
+            _ => None
+        };
+        assigned.push((id, pinned, listener));
+    }
 
-    // ThreadPool with user specified number of threads
-    let thread_pool = ThreadPool::new(threads);
+    assigned
+}
 
-    // Our I/O queue for Connections needing various I/O operations.
-    let arc_io_queue = Arc::new(Mutex::new(Vec::<IoPair>::with_capacity(MAX_EVENTS as usize)));
+/// One epoll instance, and its share of the server's connections.
+struct Reactor {
+    epoll: Arc<Epoll>,
+    /// Connections accepted, but not yet added to epoll
+    new_connections: NewConnectionSlab,
+    connection_slab: ConnectionSlab,
+    /// Hands timers to this reactor's event loop
+    scheduler: Scheduler
+}
 
-    // Start the I/O Sentinel
-    let t_pool_clone = thread_pool.clone();
-    let handler_clone = handler.clone();
-    let io_queue = arc_io_queue.clone();
-    thread::Builder::new()
-        .name("I/O Sentinel".to_string())
-        .spawn(move || {
-            io_sentinel(io_queue, t_pool_clone, handler_clone)
-        })
-        .unwrap();
+impl Reactor {
+    /// Hands the connection to the event loop, to be added to epoll.
+    fn push(&self, connection: Connection) {
+        { // Mutex lock
+            let mut slab = match self.new_connections.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
 
-    // Scratch space for epoll returned events
-    let mut event_buffer = Vec::<libc::epoll_event>::with_capacity(MAX_EVENTS as usize);
-    event_buffer.set_len(MAX_EVENTS as usize);
+            (*slab).push(connection);
+        } // Mutex unlock
 
-    info!("Starting epoll_wait loop...");
-    loop {
-        // Remove any connections in an error'd state.
-        remove_stale_connections(&connection_slab, &thread_pool, &handler);
+        self.epoll.wake();
+    }
+}
 
-        // Insert any newly received connections into the connection_slab
-        insert_new_connections(&new_connections, &connection_slab);
+/// Everything needed to turn an accepted, or connecting, socket into a connection.
+#[derive(Clone)]
+pub struct Acceptor {
+    reactors: Arc<Vec<Reactor>>,
+    /// Reactor every connection is added to, or None to take turns between all of them
+    pinned: Option<usize>,
+    next_reactor: Arc<AtomicUsize>,
+    handler: EventHandler,
+    tx_limits: TxLimits,
+    timeouts: Timeouts
+}
 
-        // Check for any new events
-        let result = libc::epoll_wait(epfd, event_buffer.as_mut_ptr(), MAX_EVENTS, MAX_WAIT);
-        if result < 0 {
-            let err = Error::from_raw_os_error(errno().0 as i32);
-            error!("During epoll_wait: {}", err);
-            panic!();
-        }
+impl Acceptor {
+    /// Creates a connection belonging to the next reactor, which it is then pushed to.
+    fn new_connection(&self,
+                      id: ConnectionId,
+                      fd: RawFd,
+                      listener: Option<ListenerId>,
+                      peer_credentials: Option<PeerCredentials>,
+                      stream: Box<dyn Stream>,
+                      context: Box<dyn Any + Send + Sync>)
+                      -> (&Reactor, Connection)
+    {
+        let index = match self.pinned {
+            Some(index) => index,
+            None => self.next_reactor.fetch_add(1, Ordering::Relaxed) % self.reactors.len()
+        };
+        let reactor = &self.reactors[index];
+
+        let connection = Connection {
+            id,
+            fd,
+            peer_credentials,
+            listener,
+            token: 0,
+            epoll: reactor.epoll.clone(),
+            err_mutex: Mutex::new(None),
+            tx_mutex: Mutex::new(TxState::new(self.tx_limits)),
+            stream: Mutex::new(stream),
+            context,
+            handler: self.handler.clone(),
+            accepted_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            first_byte_received: AtomicBool::new(false),
+            connecting: AtomicBool::new(false),
+            timeouts: self.timeouts,
+            scheduler: reactor.scheduler.clone()
+        };
 
-        let num_events = result as usize;
-        update_io_events(&connection_slab, &arc_io_queue, &event_buffer[0..num_events]);
+        (reactor, connection)
     }
 }
 
-/// Traverses through the connection slab and creates a list of connections that need dropped,
-/// then traverses that list, drops them, and informs the handler of client drop.
-unsafe fn remove_stale_connections(connection_slab: &ConnectionSlab,
-                                   thread_pool: &ThreadPool,
-                                   handler: &EventHandler)
+fn listener_loop(listener_id: ListenerId,
+                 listener: Arc<Listener>,
+                 acceptor: Acceptor,
+                 shutdown: Arc<Shutdown>)
 {
-    let slab_ptr = (*connection_slab).inner.get();
-    let slab_len = (*slab_ptr).len() as isize;
-
-    let mut x: isize = 0;
-    while x < slab_len {
-        let connection_opt = (*slab_ptr)[x as usize].as_ref();
-        x += 1;
-
-        if connection_opt.is_none() {
-            continue;
-        }
-
-        let arc_connection = connection_opt.unwrap();
-        let mut err_state: Option<Error> = None;
-        { // Mutex lock
-            let mut guard = match arc_connection.err_mutex.lock() {
-                Ok(g) => g,
-                Err(p) => p.into_inner()
-            };
+    info!("Incoming connection listener {} started", listener_id);
 
-            if (&mut *guard).is_some() {
-                let err_kind = (&mut *guard).as_ref().unwrap().kind();
-                let err_desc = (&mut *guard).as_ref().unwrap().description();
-                err_state = Some(Error::new(err_kind, err_desc));
+    loop {
+        match listener.accept() {
+            Ok(Some(fd)) => {
+                let peer_credentials = listener.peer_credentials(fd);
+                handle_new_connection(fd, listener_id, peer_credentials, &acceptor)
             }
-        } // Mutex unlock
+            // Shutdown::trigger interrupted accept
+            Ok(None) => break,
+            Err(e) => {
+                if shutdown.is_triggered() {
+                    break;
+                }
+                error!("Accepting connection: {}", e);
+            }
+        };
+    }
 
-        match err_state {
-            Some(err) => {
-                trace!("Found stale connection");
-                let arc_connection = (*slab_ptr).remove((x - 1) as usize).unwrap();
+    debug!("Listener loop {} finished", listener_id);
+}
 
-                // Inform kernel we're done
-                close_connection(&arc_connection);
+/// Waits for a successor process to connect to the handoff socket, then shuts the server down
+/// so the event loop hands off to it.
+fn handoff_loop(listener: Arc<Listener>, shutdown: Arc<Shutdown>) {
+    info!("Handoff listener started");
 
-                // Inform the consumer connection is no longer valid
-                let fd = (*arc_connection).fd;
-                let handler_clone = (*handler).clone();
-                thread_pool.execute(move || {
-                    let EventHandler(ptr) = handler_clone;
-                    (*ptr).on_connection_removed(fd, err);
-                });
+    loop {
+        match listener.accept() {
+            Ok(Some(fd)) => {
+                let stream = unsafe { UnixStream::from_raw_fd(fd) };
+                if let Err(err) = handoff::check_peer(fd) {
+                    error!("Refusing handoff: {}", err);
+                    continue;
+                }
 
-                x -= 1;
+                info!("Successor connected, shutting down to hand off");
+                shutdown.hand_off(stream);
+                break;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                if shutdown.is_triggered() {
+                    break;
+                }
+                error!("Accepting successor: {}", e);
             }
-            None => { }
         };
     }
-}
 
-/// Closes the connection's underlying file descriptor
-unsafe fn close_connection(connection: &Arc<Connection>) {
-    let fd = (*connection).fd;
-    debug!("Closing fd: {}", fd);
+    debug!("Handoff listener finished");
+}
 
-    let result = libc::close(fd);
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("Closing fd: {}    {}", fd, err);
-    }
+fn setup_listener_options(listener_id: ListenerId, fd: RawFd, handler: &EventHandler)
+    -> Result<(), HydrogenError>
+{
+    info!("Setting up listener options");
+    call_handler(handler, None, "on_server_created", || handler.on_server_created(listener_id, fd))
+        .map_err(|(callback, msg)| HydrogenError::HandlerPanic(callback, msg))
 }
 
-/// Transfers Connections from the new_connections slab to the "main" connection_slab.
-unsafe fn insert_new_connections(new_connections: &NewConnectionSlab,
-                                 connection_slab: &ConnectionSlab)
+fn handle_new_connection(fd: RawFd,
+                         listener_id: ListenerId,
+                         peer_credentials: Option<PeerCredentials>,
+                         acceptor: &Acceptor)
 {
-    let mut new_slab = match new_connections.lock() {
-        Ok(g) => g,
-        Err(p) => p.into_inner()
+    debug!("New connection received");
+    let id = ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed));
+
+    // Execute EventHandler's constructor
+    let handler = &acceptor.handler;
+    let result = call_handler(handler, Some(id), "on_new_connection", || {
+        handler.on_new_connection(id, listener_id, fd)
+    });
+    let (stream, context) = match result {
+        Ok(stream_and_context) => stream_and_context,
+        Err(_) => {
+            // There is no stream to hold the fd, and no connection to remove later
+            unsafe { libc::close(fd); }
+            return;
+        }
     };
 
-    let num_connections = (&mut *new_slab).len();
-    let arc_main_slab = (*connection_slab).inner.get();
-    for _ in 0..num_connections {
-        let connection = (&mut *new_slab).remove(0).unwrap();
-        let arc_connection = Arc::new(connection);
-        (*arc_main_slab).insert(arc_connection.clone());
-        add_connection_to_epoll(&arc_connection);
-    }
+    let (reactor, connection) =
+        acceptor.new_connection(id, fd, Some(listener_id), peer_credentials, stream, context);
+    reactor.push(connection);
 }
 
-/// Adds a new connection to the epoll interest list.
-unsafe fn add_connection_to_epoll(arc_connection: &Arc<Connection>) {
-    let fd = (*arc_connection).fd;
-    debug!("Adding fd {} to epoll", fd);
-    let result = libc::epoll_ctl(epfd,
-                       libc::EPOLL_CTL_ADD,
-                       fd,
-                       &mut libc::epoll_event { events: DEFAULT_EVENTS as u32, u64: fd as u64 });
+/// Adds a connection handed off by the previous server, as `handle_new_connection` does for
+/// accepted ones.
+fn adopt_connection(handed_off: HandedOffConnection, acceptor: &Acceptor) {
+    let HandedOffConnection { listener: listener_id, fd, blob, queued } = handed_off;
+    let fd = fd.into_raw_fd();
+    let id = ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed));
+
+    let handler = &acceptor.handler;
+    let result = call_handler(handler, Some(id), "on_connection_adopted", || {
+        handler.on_connection_adopted(id, listener_id, fd, blob)
+    });
+    let (stream, context) = match result {
+        Ok(Some(stream_and_context)) => stream_and_context,
+        _ => {
+            debug!("Closing handed off fd: {}", fd);
+            unsafe { libc::close(fd); }
+            return;
+        }
+    };
 
-   if result < 0 {
-       let err = Error::from_raw_os_error(errno().0 as i32);
-       error!("Adding fd: {} to epoll:   {}", fd, err);
+    let peer_credentials = if listener::is_unix_socket(fd) {
+        listener::read_peer_credentials(fd)
+    } else {
+        None
+    };
+    let (reactor, mut connection) =
+        acceptor.new_connection(id, fd, Some(listener_id), peer_credentials, stream, context);
 
-       let mut err_state = match arc_connection.err_mutex.lock() {
-           Ok(g) => g,
-           Err(p) => p.into_inner()
-       };
+    // It was established, and had most likely been sent to, long before it was handed off
+    connection.first_byte_received = AtomicBool::new(true);
+    if !queued.is_empty() {
+        let tx_state = match connection.tx_mutex.get_mut() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        tx_state.enqueue(queued);
+    }
 
-       *err_state = Some(err);
-   }
+    reactor.push(connection);
 }
 
-/// Re-arms a connection in the epoll interest list with the event mask.
-unsafe fn rearm_connection_in_epoll(arc_connection: &Arc<Connection>, flags: i32) {
-    let fd = arc_connection.fd;
-    let events = DEFAULT_EVENTS | flags;
+/// Starts a non-blocking connect to `addr`, and adds the connecting socket to the next reactor,
+/// which reports the outcome once epoll reports the socket writable.
+pub fn connect<F>(acceptor: &Acceptor,
+                  shutdown: &Shutdown,
+                  addr: &SocketAddr,
+                  stream_factory: F)
+                  -> Result<ConnectionId, HydrogenError>
+    where F: FnOnce(ConnectionId, RawFd) -> (Box<dyn Stream>, Box<dyn Any + Send + Sync>)
+{
+    let shutting_down = || {
+        HydrogenError::Connect(Error::new(ErrorKind::ConnectionAborted, "Server shutdown"))
+    };
+    if shutdown.is_triggered() {
+        return Err(shutting_down());
+    }
 
-    trace!("EPOLL_CTL_MOD   fd: {}    flags: {:#b}", fd, (flags as u32));
+    debug!("Connecting to {}", addr);
+    let fd = match listener::connect_tcp(addr) {
+        Ok(fd) => fd,
+        Err(err) => {
+            debug!("Connecting to {}: {}", addr, err);
+            return Err(HydrogenError::Connect(err));
+        }
+    };
+    let id = ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed));
+
+    // The factory belongs to the caller, so its panic does too, once the fd is taken care of
+    let (stream, context) = match panic::catch_unwind(AssertUnwindSafe(|| stream_factory(id, fd))) {
+        Ok(stream_and_context) => stream_and_context,
+        Err(payload) => {
+            unsafe { libc::close(fd); }
+            panic::resume_unwind(payload);
+        }
+    };
 
-    let result = libc::epoll_ctl(epfd,
-                       libc::EPOLL_CTL_MOD,
-                       fd,
-                       &mut libc::epoll_event { events: events as u32, u64: fd as u64 });
+    let (reactor, connection) = acceptor.new_connection(id, fd, None, None, stream, context);
 
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("EPOLL_CTL_MOD   fd: {}    {}", fd, err);
+    // The peer was dialled rather than accepted, so first_byte_timeout does not apply
+    connection.first_byte_received.store(true, Ordering::Relaxed);
+    connection.connecting.store(true, Ordering::SeqCst);
 
-        let mut err_state = match arc_connection.err_mutex.lock() {
+    { // Mutex lock
+        let mut slab = match reactor.new_connections.lock() {
             Ok(g) => g,
             Err(p) => p.into_inner()
         };
 
-        *err_state = Some(err);
-    }
-}
+        // Checked while holding the lock, so the connection is either picked up by the
+        // shutdown, or never added at all
+        if shutdown.is_triggered() {
+            drop(connection);
+            unsafe { libc::close(fd); }
+            return Err(shutting_down());
+        }
 
-/// Traverses the ConnectionSlab and updates any connection's state reported changed by epoll.
-unsafe fn update_io_events(connection_slab: &ConnectionSlab,
-                           arc_io_queue: &IoQueue,
-                           events: &[libc::epoll_event])
-{
-    const READ_EVENT: u32 = libc::EPOLLIN as u32;
-    const WRITE_EVENT: u32 = libc::EPOLLOUT as u32;
-    const CLOSE_EVENT: u32 = (libc::EPOLLRDHUP | libc::EPOLLERR | libc::EPOLLHUP) as u32;
+        (*slab).push(connection);
+    } // Mutex unlock
 
-    for event in events.iter() {
-        // Locate the connection this event is for
-        let fd = event.u64 as RawFd;
+    reactor.epoll.wake();
 
-        trace!("Epoll event for fd: {}    flags: {:#b}", fd, event.events);
+    Ok(id)
+}
 
-        let find_result = find_connection_from_fd(fd, connection_slab);
-        if find_result.is_err() {
-            error!("Unable to find fd {} in ConnectionSlab", fd);
-            continue;
-        }
+/// Everything the event loops share with the rest of the server.
+#[derive(Clone)]
+struct EventLoop {
+    reactors: Arc<Vec<Reactor>>,
+    groups: Groups,
+    handler: EventHandler,
+    thread_pool: ThreadPool,
+    shutdown: Arc<Shutdown>
+}
 
-        let arc_connection = find_result.unwrap();
+/// Main event loop of the reactor at `index`.
+///
+/// Once the server is shutting down, the first reactor's event loop joins `threads`, which are
+/// every other thread of the server, then finishes the shutdown for every reactor.
+fn event_loop(context: EventLoop, index: usize, threads: Vec<JoinHandle<()>>) {
+    let EventLoop {
+        reactors,
+        groups,
+        handler,
+        thread_pool,
+        shutdown
+    } = context;
+    let reactor = &reactors[index];
+
+    info!("Event loop {} starting...", index);
+    // Anything needing attention outside of epoll reported I/O writes to the wake eventfd, so
+    // the only other reason to stop waiting is the next timer
+    const MAX_WAIT: i32 = -1;
 
-        // Error/hangup occurred?
-        let close_event = (event.events & CLOSE_EVENT) > 0;
-        if close_event {
-            { // Mutex lock
-                let mut err_opt = match arc_connection.err_mutex.lock() {
-                    Ok(g) => g,
-                    Err(p) => p.into_inner()
-                };
+    // Scratch space for epoll returned events
+    let empty_event = libc::epoll_event { events: 0, u64: 0 };
+    let mut event_buffer = vec![empty_event; MAX_EVENTS];
 
-                *err_opt = Some(Error::new(ErrorKind::ConnectionAborted, "ConnectionAborted"));
-            } // Mutex unlock
-            continue;
-        }
+    // Timeout checks and consumer scheduled timers
+    let mut timers = TimerWheel::<TimerEvent>::new();
+    let mut expired = Vec::<TimerEvent>::new();
 
-        // Read or write branch
-        let read_available = (event.events & READ_EVENT) > 0;
-        let write_available = (event.events & WRITE_EVENT) > 0;
-        if !read_available && !write_available {
-            trace!("Event was neither read nor write: assuming hangup");
-            { // Mutex lock
-                let mut err_opt = match arc_connection.err_mutex.lock() {
-                    Ok(g) => g,
-                    Err(p) => p.into_inner()
-                };
+    info!("Starting epoll_wait loop...");
+    while !shutdown.is_triggered() {
+        // Remove any connections in an error'd state.
+        remove_stale_connections(&reactor.connection_slab, &groups, &thread_pool, &handler);
 
-                *err_opt = Some(Error::new(ErrorKind::ConnectionAborted, "ConnectionAborted"));
-            } // Mutex unlock
-            continue;
-        }
+        // Insert any newly received connections into the connection_slab
+        insert_new_connections(&reactor.new_connections, &reactor.connection_slab, &mut timers);
 
-        let io_event = if read_available && write_available {
-            trace!("Event: RW");
-            IoEvent::ReadWriteAvailable
-        } else if read_available {
-            trace!("Event: R");
-            IoEvent::ReadAvailable
-        } else {
-            trace!("Event W");
-            IoEvent::WriteAvailable
+        // Pick up any timers scheduled since the last pass
+        reactor.scheduler.drain_into(&mut timers);
+
+        // Check for any new events
+        let wait = match timers.next_timeout(Instant::now()) {
+            Some(timeout) => duration_to_wait(timeout),
+            None => MAX_WAIT
         };
+        let num_events = match reactor.epoll.wait(&mut event_buffer[..], wait) {
+            Ok(num_events) => num_events,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                error!("During epoll_wait: {}", err);
+                let _ = call_handler(&handler, None, "on_server_error", || {
+                    handler.on_server_error(HydrogenError::EpollWait(err))
+                });
 
-        let io_pair = IoPair {
-            event: io_event,
-            arc_connection: arc_connection
+                shutdown.trigger();
+                break;
+            }
         };
 
-        trace!("Adding event to queue");
-        { // Mutex lock
-            let mut io_queue = match arc_io_queue.lock() {
-                Ok(g) => g,
-                Err(p) => p.into_inner()
-            };
+        update_io_events(reactor, &thread_pool, &handler, &event_buffer[0..num_events]);
 
-            (*io_queue).push(io_pair);
-        } // Mutex unlock
+        timers.advance(Instant::now(), &mut expired);
+        if !expired.is_empty() {
+            handle_expired_timers(&reactor.connection_slab,
+                                  &handler,
+                                  &thread_pool,
+                                  &reactor.scheduler,
+                                  &mut timers,
+                                  &mut expired);
+        }
     }
-}
 
-/// Given a fd and ConnectionSlab, returns the Connection associated with fd.
-unsafe fn find_connection_from_fd(fd: RawFd,
-                                  connection_slab: &ConnectionSlab)
-                                  -> Result<Arc<Connection>, ()>
-{
-    let slab_ptr = (*connection_slab).inner.get();
-    for ref arc_connection in (*slab_ptr).iter() {
-        if (*arc_connection).fd == fd {
-            return Ok((*arc_connection).clone());
-        }
+    if index != 0 {
+        debug!("Event loop {} finished", index);
+        return;
     }
 
-    Err(())
-}
+    info!("Shutting down...");
 
-unsafe fn io_sentinel(arc_io_queue: IoQueue, thread_pool: ThreadPool, handler: EventHandler) {
-    info!("Starting I/O Sentinel");
-    // We want to wake up with the same interval consitency as the epoll_wait loop.
-    // Plus a few ms for hopeful non-interference from mutex contention.
-    let _100ms = 1000000 * 100;
-    let wait_interval = Duration::new(0, _100ms);
+    // No more connections will be accepted, or events dispatched, once these return
+    for thread in threads {
+        let _ = thread.join();
+    }
 
-    loop {
-        thread::sleep(wait_interval);
+    // Wait for any in-flight I/O, then flush out anything that error'd during it
+    thread_pool.join();
+    for reactor in reactors.iter() {
+        remove_stale_connections(&reactor.connection_slab, &groups, &thread_pool, &handler);
+    }
+    thread_pool.join();
+
+    // Everything left is still healthy, and is either handed off to a successor, or dropped
+    // because of the shutdown
+    let mut connections = Vec::<Arc<Connection>>::new();
+    for reactor in reactors.iter() {
+        connections.append(&mut take_all_connections(&reactor.new_connections,
+                                                     &reactor.connection_slab));
+    }
+    if let Some(pending) = shutdown.take_handoff() {
+        let (handed_off, remaining) = hand_off(pending, connections, &handler);
+        remove_connections(&handed_off, "Handed off to successor", &groups, &handler);
+        connections = remaining;
+    }
+    remove_connections(&connections, "Server shutdown", &groups, &handler);
 
-        let io_queue;
-        { // Mutex lock
-            let mut queue = match arc_io_queue.lock() {
-                Ok(g) => g,
-                Err(p) => p.into_inner()
-            };
+    // Timers that never came due are dropped along with the wheels
+    for reactor in reactors.iter() {
+        reactor.scheduler.clear();
+    }
 
-            let empty_queue = Vec::<IoPair>::with_capacity(MAX_EVENTS as usize);
-            io_queue = mem::replace(&mut (*queue), empty_queue);
-        } // Mutex unlock
+    info!("Server shutdown complete");
+}
 
-        if io_queue.len() > 0 {
-            trace!("Processing {} I/O events", io_queue.len());
-        }
-
-        for ref io_pair in io_queue.iter() {
-            let io_event = io_pair.event.clone();
-            let handler_clone = handler.clone();
-            let arc_connection = io_pair.arc_connection.clone();
-            thread_pool.execute(move || {
-                let mut rearm_events = 0i32;
-                if io_event == IoEvent::WriteAvailable
-                    || io_event == IoEvent::ReadWriteAvailable
-                {
-                    let flags = handle_write_event(arc_connection.clone());
-                    if flags == -1 {
-                        return;
-                    }
-                    rearm_events |= flags;
-                }
-                if io_event == IoEvent::ReadAvailable
-                    || io_event == IoEvent::ReadWriteAvailable
-                {
-                    let flags = handle_read_event(arc_connection.clone(), handler_clone);
-                    if flags == -1 {
-                        return;
-                    }
-                    rearm_events |= flags;
+/// Traverses through the connection slab and creates a list of connections that need dropped,
+/// then traverses that list, drops them, and informs the handler of client drop.
+fn remove_stale_connections(connection_slab: &ConnectionSlab,
+                            groups: &Groups,
+                            thread_pool: &ThreadPool,
+                            handler: &EventHandler)
+{
+    let mut removed = Vec::<(Arc<Connection>, Error)>::new();
+    { // RwLock write
+        let mut connections = match connection_slab.write() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        let mut stale = Vec::<(Token, Error)>::new();
+        for (token, arc_connection) in connections.iter() {
+            { // Mutex lock
+                let mut guard = match arc_connection.err_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                // The original goes to the handler, keeping any error it wraps
+                if let Some(err) = guard.take() {
+                    *guard = Some(Error::new(err.kind(), err.to_string()));
+                    stale.push((token, err));
                 }
+            } // Mutex unlock
+        }
 
-                rearm_connection_in_epoll(&arc_connection, rearm_events);
-            });
+        for (token, err) in stale {
+            removed.push((connections.remove(token).unwrap(), err));
         }
+    } // RwLock unlock
+
+    for (arc_connection, err) in removed {
+        trace!("Found stale connection");
+
+        // Inform kernel we're done
+        close_connection(&arc_connection);
+
+        let id = arc_connection.id;
+        groups.remove_connection(id);
+
+        // Inform the consumer connection is no longer valid
+        let handler_clone = handler.clone();
+        let connecting = arc_connection.connecting.load(Ordering::SeqCst);
+        thread_pool.execute(move || report_removal(&handler_clone, id, connecting, err));
     }
 }
 
-/// Handles an EPOLLOUT event. An empty buffer is sent down the tx line to
-/// force whatever was left in the tx_buffer into the kernel's outbound buffer.
-unsafe fn handle_write_event(arc_connection: Arc<Connection>) -> i32 {
-    debug!("Handling a write backlog event...");
-    let err;
-    { // Mutex lock
-        let _ = match arc_connection.tx_mutex.lock() {
+/// Takes every connection out of the server, whether or not it has made it into epoll yet.
+/// Used during shutdown, once the I/O threadpool has been drained.
+fn take_all_connections(new_connections: &NewConnectionSlab, connection_slab: &ConnectionSlab)
+    -> Vec<Arc<Connection>>
+{
+    let mut connections;
+    { // RwLock write
+        let mut main_slab = match connection_slab.write() {
             Ok(g) => g,
             Err(p) => p.into_inner()
         };
 
-        // Get a pointer into UnsafeCell<Stream>
-        let stream_ptr = arc_connection.stream.get();
-
-        let empty = Vec::<u8>::new();
-        let write_result = (*stream_ptr).send(&empty[..]);
-        if write_result.is_ok() {
-            debug!("Cleared backlog");
-            return 0i32;
-        }
-
-        err = write_result.unwrap_err();
-        if err.kind() == ErrorKind::WouldBlock {
-            debug!("Backlog still not cleared, returning EPOLLOUT flags for fd");
-            return libc::EPOLLOUT;
+        let tokens: Vec<Token> = main_slab.iter().map(|(token, _)| token).collect();
+        connections = Vec::<Arc<Connection>>::with_capacity(main_slab.len());
+        for token in tokens {
+            connections.push(main_slab.remove(token).unwrap());
         }
-    } // Mutex unlock
+    } // RwLock unlock
 
     { // Mutex lock
-        let mut err_state = match arc_connection.err_mutex.lock() {
+        let mut new_slab = match new_connections.lock() {
             Ok(g) => g,
             Err(p) => p.into_inner()
         };
 
-        *err_state = Some(err);
+        for connection in (*new_slab).drain(..) {
+            connections.push(Arc::new(connection));
+        }
     } // Mutex unlock
 
-    return -1i32;
+    connections
 }
 
-unsafe fn handle_read_event(arc_connection: Arc<Connection>, handler: EventHandler) -> i32 {
-    trace!("Handling read event");
-    let stream_ptr = arc_connection.stream.get();
+/// Drops every connection in `connections`, and informs the handler of each drop, with
+/// `ErrorKind::ConnectionAborted` and `reason`.
+fn remove_connections(connections: &[Arc<Connection>],
+                      reason: &'static str,
+                      groups: &Groups,
+                      handler: &EventHandler)
+{
+    debug!("Removing {} connections: {}", connections.len(), reason);
 
-    // Attempt recv
-    match (*stream_ptr).recv() {
-        Ok(mut queue) => {
-            trace!("Read {} msgs", queue.len());
-            for msg in queue.drain(..) {
-                let EventHandler(ptr) = handler;
-                let hydrogen_socket = HydrogenSocket::new(arc_connection.clone(),
-                                                          rearm_connection_in_epoll);
-                (*ptr).on_data_received(hydrogen_socket, msg);
-            }
-            return libc::EPOLLIN;
-        }
+    for arc_connection in connections.iter() {
+        { // Mutex lock
+            let mut err_state = match arc_connection.err_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
 
-        Err(err) => {
-            let kind = err.kind();
-            if kind == ErrorKind::WouldBlock {
-                trace!("ErrorKind::WouldBlock");
-                return libc::EPOLLIN;
+            *err_state = Some(Error::new(ErrorKind::ConnectionAborted, reason));
+        } // Mutex unlock
+
+        close_connection(arc_connection);
+        groups.remove_connection(arc_connection.id);
+
+        let id = arc_connection.id;
+        let err = Error::new(ErrorKind::ConnectionAborted, reason);
+        report_removal(handler, id, arc_connection.connecting.load(Ordering::SeqCst), err);
+    }
+}
+
+/// Informs the handler a connection has been removed, or, if it was removed before its connect
+/// finished, that the connect failed.
+fn report_removal(handler: &EventHandler, id: ConnectionId, connecting: bool, err: Error) {
+    if connecting {
+        let _ = call_handler(handler, Some(id), "on_connect_result", || {
+            handler.on_connect_result(id, Err(err))
+        });
+    } else {
+        let _ = call_handler(handler, Some(id), "on_connection_removed", || {
+            handler.on_connection_removed(id, err)
+        });
+    }
+}
+
+/// Sends every listener, then each connection the handler chooses to hand off, to the
+/// successor. Returns the connections handed off, and those that were not.
+///
+/// If the successor goes away part way through, whatever has not been sent is kept, to be
+/// closed along with everything else.
+fn hand_off(pending: PendingHandoff,
+            connections: Vec<Arc<Connection>>,
+            handler: &EventHandler)
+            -> (Vec<Arc<Connection>>, Vec<Arc<Connection>>)
+{
+    let PendingHandoff { stream, listeners } = pending;
+    info!("Handing off {} listeners to successor...", listeners.len());
+
+    let mut sender = match Sender::new(stream) {
+        Ok(sender) => sender,
+        Err(err) => {
+            report_handoff_error(handler, err);
+            return (Vec::new(), connections);
+        }
+    };
+    for &(id, ref listener) in listeners.iter() {
+        if let Err(err) = sender.send_listener(id, listener.as_raw_fd()) {
+            report_handoff_error(handler, err);
+            return (Vec::new(), connections);
+        }
+        listener.mark_handed_off();
+    }
+
+    let mut handed_off = Vec::<Arc<Connection>>::new();
+    let mut remaining = Vec::<Arc<Connection>>::new();
+    let mut connections = connections.into_iter();
+    while let Some(arc_connection) = connections.next() {
+        match hand_off_connection(&mut sender, &arc_connection, handler) {
+            Ok(true) => handed_off.push(arc_connection),
+            Ok(false) => remaining.push(arc_connection),
+            Err(err) => {
+                report_handoff_error(handler, err);
+                remaining.push(arc_connection);
+                remaining.extend(connections);
+                return (handed_off, remaining);
             }
+        }
+    }
 
-            if kind != ErrorKind::UnexpectedEof
-                && kind != ErrorKind::ConnectionReset
-                && kind != ErrorKind::ConnectionAborted
+    if let Err(err) = sender.finish() {
+        report_handoff_error(handler, err);
+    }
+
+    info!("Handed off {} connections", handed_off.len());
+    (handed_off, remaining)
+}
+
+/// Sends the connection to the successor if the handler chooses to hand it off. Returns true if
+/// it was sent, after which nothing more is ever written to it from this process.
+fn hand_off_connection(sender: &mut Sender,
+                       arc_connection: &Arc<Connection>,
+                       handler: &EventHandler)
+                       -> Result<bool, Error>
+{
+    // The successor has no listener to adopt an outbound connection under
+    let listener = match arc_connection.listener {
+        Some(listener) => listener,
+        None => return Ok(false)
+    };
+    if arc_connection.is_errored() {
+        return Ok(false);
+    }
+
+    let id = arc_connection.id;
+    let socket = HydrogenSocket::new(arc_connection.clone());
+    let result = call_handler(handler, Some(id), "on_connection_handoff", || {
+        handler.on_connection_handoff(socket)
+    });
+    let blob = match result {
+        Ok(Some(blob)) => blob,
+        _ => return Ok(false)
+    };
+    if blob.len() > handoff::MAX_BLOB_LEN {
+        error!("Handoff blob of connection {} is {} bytes, over the limit", id, blob.len());
+        return Ok(false);
+    }
+
+    // Held until the connection is in an error'd state, so no send from a ServerHandle can
+    // slip in once the queue has been taken
+    let mut tx_state = match arc_connection.tx_mutex.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+    if arc_connection.is_errored() || tx_state.pending_close.is_some() {
+        return Ok(false);
+    }
+
+    let queued = match tx_state.take_queued() {
+        Ok(queued) => queued,
+        Err(err) => {
+            error!("Reading queued data of fd: {}    {}", arc_connection.fd, err);
+            return Ok(false);
+        }
+    };
+    if queued.len() > handoff::MAX_QUEUED_LEN {
+        error!("Connection {} has {} bytes queued, over the handoff limit", id, queued.len());
+        return Ok(false);
+    }
+    sender.send_connection(listener, arc_connection.fd, &blob[..], &queued[..])?;
+
+    let mut err_state = match arc_connection.err_mutex.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+    *err_state = Some(Error::new(ErrorKind::ConnectionAborted, "Handed off to successor"));
+
+    Ok(true)
+}
+
+fn report_handoff_error(handler: &EventHandler, err: Error) {
+    error!("Handing off to successor: {}", err);
+    let _ = call_handler(handler, None, "on_server_error", || {
+        handler.on_server_error(HydrogenError::Handoff(err))
+    });
+}
+
+/// Closes the connection's underlying file descriptor. The connection must already be in an
+/// error'd state, so that no HydrogenSocket writes to, and no read event reads from, the fd
+/// after it has been closed.
+fn close_connection(connection: &Arc<Connection>) {
+    let fd = connection.fd;
+    debug!("Closing fd: {}", fd);
+
+    let _tx_state = match connection.tx_mutex.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    // A read in progress on a pool thread holds the stream, the fd must outlive it
+    let _stream = match connection.stream.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    let result = unsafe { libc::close(fd) };
+    if result < 0 {
+        let err = Error::from_raw_os_error(errno().0 as i32);
+        error!("Closing fd: {}    {}", fd, err);
+    }
+}
+
+/// Transfers Connections from the new_connections list to the "main" connection_slab.
+fn insert_new_connections(new_connections: &NewConnectionSlab,
+                          connection_slab: &ConnectionSlab,
+                          timers: &mut TimerWheel<TimerEvent>)
+{
+    let mut new_slab = match new_connections.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    let mut main_slab = match connection_slab.write() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    let now = Instant::now();
+    for connection in (*new_slab).drain(..) {
+        let arc_connection = main_slab.insert(connection);
+        add_connection_to_epoll(&arc_connection);
+
+        if arc_connection.timeouts.any() {
+            schedule_timeout_check(&arc_connection, timers, now);
+        }
+    }
+}
+
+/// Re-checks every connection whose timeout check has come due, and runs every consumer timer
+/// that has come due on the threadpool. Timed out connections are put into an error'd state, to
+/// be removed with the rest of the stale connections.
+fn handle_expired_timers(connection_slab: &ConnectionSlab,
+                         handler: &EventHandler,
+                         thread_pool: &ThreadPool,
+                         scheduler: &Scheduler,
+                         timers: &mut TimerWheel<TimerEvent>,
+                         expired: &mut Vec<TimerEvent>)
+{
+    let connections = match connection_slab.read() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    let now = Instant::now();
+    for event in expired.drain(..) {
+        match event {
+            TimerEvent::TimeoutCheck(token) => {
+                // Connections that have since been removed are simply not found
+                if let Some(arc_connection) = connections.get(token) {
+                    schedule_timeout_check(arc_connection, timers, now);
+                }
+            }
+            TimerEvent::Scheduled(timer) => {
+                if timer.is_cancelled() {
+                    continue;
+                }
+
+                let scheduler = scheduler.clone();
+                let handler = handler.clone();
+                thread_pool.execute(move || ScheduledTimer::run(timer, &scheduler, &handler));
+            }
+        }
+    }
+}
+
+/// Times out the connection if any of its timeouts have passed, otherwise schedules the next
+/// check for the earliest one that could.
+///
+/// Activity is only recorded on the connection itself, the I/O threads never touch the wheel.
+/// A check that comes due after more activity finds a later deadline and is simply rescheduled.
+fn schedule_timeout_check(arc_connection: &Arc<Connection>,
+                          timers: &mut TimerWheel<TimerEvent>,
+                          now: Instant)
+{
+    let timeouts = arc_connection.timeouts;
+    let mut next_check: Option<Instant> = None;
+    {
+        let mut check = |deadline: Instant, reason: &str| {
+            if deadline <= now {
+                debug!("Connection {} timed out: {}", arc_connection.id, reason);
+                arc_connection.close_with(Error::new(ErrorKind::TimedOut, reason));
+                return true;
+            }
+
+            next_check = Some(next_check.map_or(deadline, |next| cmp::min(next, deadline)));
+            false
+        };
+
+        if let Some(timeout) = timeouts.first_byte {
+            if !arc_connection.first_byte_received.load(Ordering::Relaxed)
+                && check(arc_connection.accepted_at + timeout, "First byte timeout")
             {
-                error!("Unexpected during recv:   {}", err);
-            } else {
-                debug!("Received during read:    {}", err);
+                return;
+            }
+        }
+
+        if let Some(timeout) = timeouts.idle {
+            if check(arc_connection.last_activity() + timeout, "Idle timeout") {
+                return;
+            }
+        }
+
+        if let Some(timeout) = timeouts.write_stall {
+            let stalled_since = { // Mutex lock
+                let tx_state = match arc_connection.tx_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                tx_state.stalled_since()
+            }; // Mutex unlock
+
+            // Nothing queued yet, look again in case something is by then
+            let deadline = match stalled_since {
+                Some(stalled_since) => stalled_since + timeout,
+                None => now + timeout
+            };
+            if check(deadline, "Write stall timeout") {
+                return;
+            }
+        }
+    }
+
+    if let Some(deadline) = next_check {
+        timers.insert(deadline, TimerEvent::TimeoutCheck(arc_connection.token));
+    }
+}
+
+/// Converts a timer's timeout into milliseconds for epoll_wait, rounding up so the timer has
+/// always expired by the time epoll_wait returns.
+fn duration_to_wait(timeout: Duration) -> i32 {
+    let ms = timeout.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(timeout.subsec_nanos().div_ceil(1_000_000)));
+
+    cmp::min(ms, i32::MAX as u64) as i32
+}
+
+/// Adds a new connection to the epoll interest list.
+fn add_connection_to_epoll(arc_connection: &Arc<Connection>) {
+    let fd = arc_connection.fd;
+    debug!("Adding fd {} to epoll", fd);
+
+    // Only connections handed off by a previous server start out with anything queued, and a
+    // connect has finished once the socket is writable
+    let mut events = DEFAULT_EVENTS;
+    { // Mutex lock
+        let tx_state = match arc_connection.tx_mutex.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        if tx_state.is_backlogged() || arc_connection.connecting.load(Ordering::SeqCst) {
+            events |= libc::EPOLLOUT;
+        }
+    } // Mutex unlock
+
+    let result = arc_connection.epoll.add(fd, events, arc_connection.token);
+    if let Err(err) = result {
+        error!("Adding fd: {} to epoll:   {}", fd, err);
+
+        let mut err_state = match arc_connection.err_mutex.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        *err_state = Some(err);
+    }
+}
+
+/// Updates the state of any connection reported changed by epoll. Each event carries the
+/// connection's slab token, so finding the connection is O(1).
+fn update_io_events(reactor: &Reactor,
+                    thread_pool: &ThreadPool,
+                    handler: &EventHandler,
+                    events: &[libc::epoll_event])
+{
+    const READ_EVENT: u32 = libc::EPOLLIN as u32;
+    const WRITE_EVENT: u32 = libc::EPOLLOUT as u32;
+    const CLOSE_EVENT: u32 = (libc::EPOLLRDHUP | libc::EPOLLERR | libc::EPOLLHUP) as u32;
+
+    let connections = match reactor.connection_slab.read() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    };
+
+    for event in events.iter() {
+        // Locate the connection this event is for
+        let token = event.u64;
+        if token == WAKE_TOKEN {
+            trace!("Event loop woken");
+            reactor.epoll.clear_wake();
+            continue;
+        }
+        let arc_connection = match connections.get(token) {
+            Some(arc_connection) => arc_connection.clone(),
+            None => {
+                error!("Unable to find token {:#x} in ConnectionSlab", token);
+                continue;
             }
+        };
+
+        let flags = event.events;
+        trace!("Epoll event for fd: {}    flags: {:#b}", arc_connection.fd, flags);
+
+        // Whatever was reported, the connect has finished, and its outcome comes first
+        if arc_connection.connecting.load(Ordering::SeqCst) {
+            dispatch_connect_result(thread_pool, handler, arc_connection);
+            continue;
+        }
 
+        // Error/hangup occurred?
+        let close_event = (event.events & CLOSE_EVENT) > 0;
+        if close_event {
             { // Mutex lock
-                // If we're in a state of ShouldClose, no need to worry
-                // about any other operations...
-                let mut err_state = match arc_connection.err_mutex.lock() {
+                let mut err_opt = match arc_connection.err_mutex.lock() {
                     Ok(g) => g,
                     Err(p) => p.into_inner()
                 };
 
-                *err_state = Some(err);
+                *err_opt = Some(Error::new(ErrorKind::ConnectionAborted, "ConnectionAborted"));
             } // Mutex unlock
+            continue;
         }
-    };
 
-    return -1i32;
+        // Read or write branch
+        let read_available = (event.events & READ_EVENT) > 0;
+        let write_available = (event.events & WRITE_EVENT) > 0;
+        if !read_available && !write_available {
+            trace!("Event was neither read nor write: assuming hangup");
+            { // Mutex lock
+                let mut err_opt = match arc_connection.err_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                *err_opt = Some(Error::new(ErrorKind::ConnectionAborted, "ConnectionAborted"));
+            } // Mutex unlock
+            continue;
+        }
+
+        let io_event = if read_available && write_available {
+            trace!("Event: RW");
+            IoEvent::ReadWrite
+        } else if read_available {
+            trace!("Event: R");
+            IoEvent::Read
+        } else {
+            trace!("Event W");
+            IoEvent::Write
+        };
+
+        dispatch_io_event(thread_pool, handler, arc_connection, io_event);
+    }
+}
+
+/// Hands the I/O needed on a connection straight to the threadpool.
+fn dispatch_io_event(thread_pool: &ThreadPool,
+                     handler: &EventHandler,
+                     arc_connection: Arc<Connection>,
+                     io_event: IoEvent)
+{
+    let handler_clone = handler.clone();
+    thread_pool.execute(move || {
+        let mut rearm_events = 0i32;
+        if io_event == IoEvent::Write || io_event == IoEvent::ReadWrite {
+            let flags = handle_write_event(arc_connection.clone(), handler_clone.clone());
+            if flags == -1 {
+                return;
+            }
+            rearm_events |= flags;
+        }
+        if io_event == IoEvent::Read || io_event == IoEvent::ReadWrite {
+            let flags = handle_read_event(arc_connection.clone(), handler_clone);
+            if flags == -1 {
+                return;
+            }
+            rearm_events |= flags;
+        }
+
+        arc_connection.rearm(rearm_events);
+    });
+}
+
+/// Reports a finished connect to the handler on the threadpool, then re-arms the connection for
+/// the events of an accepted one. A failed connect is left to be reported once the connection
+/// has been removed.
+fn dispatch_connect_result(thread_pool: &ThreadPool,
+                           handler: &EventHandler,
+                           arc_connection: Arc<Connection>)
+{
+    let handler_clone = handler.clone();
+    thread_pool.execute(move || {
+        let id = arc_connection.id;
+        { // Mutex lock
+            // A send made meanwhile re-arms the connection, so the connect may be reported
+            // twice. Only the first report to get here deals with it.
+            let _tx_state = match arc_connection.tx_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if !arc_connection.connecting.load(Ordering::SeqCst) || arc_connection.is_errored() {
+                return;
+            }
+
+            if let Err(err) = listener::connect_result(arc_connection.fd) {
+                debug!("Connection {} failed to connect: {}", id, err);
+                arc_connection.close_with(err);
+                return;
+            }
+            arc_connection.connecting.store(false, Ordering::SeqCst);
+        } // Mutex unlock
+
+        debug!("Connection {} connected", id);
+        arc_connection.touch();
+        let hydrogen_socket = HydrogenSocket::new(arc_connection.clone());
+        let result = call_handler(&handler_clone, Some(id), "on_connect_result", || {
+            handler_clone.on_connect_result(id, Ok(hydrogen_socket))
+        });
+        if let Err((callback, msg)) = result {
+            arc_connection.close_with(panic_error(callback, msg));
+            return;
+        }
+
+        arc_connection.rearm(0);
+    });
+}
+
+/// Handles an EPOLLOUT event by flushing the connection's outbound queue.
+fn handle_write_event(arc_connection: Arc<Connection>, handler: EventHandler) -> i32 {
+    debug!("Handling a write backlog event...");
+    let relieved;
+    let flags;
+    let err;
+    { // Mutex lock
+        let mut tx_state = match arc_connection.tx_mutex.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        // The fd may already have been closed, and even reused
+        if arc_connection.is_errored() {
+            return -1i32;
+        }
+
+        // The stall clock restarts whenever the flush makes progress
+        let stalled_since = tx_state.stalled_since();
+        let result = { // Mutex lock
+            let mut stream = match arc_connection.stream.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            panic::catch_unwind(AssertUnwindSafe(|| tx_state.flush(&mut **stream)))
+        }; // Mutex unlock
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => {
+                drop(tx_state);
+                close_panicked(&arc_connection, "Stream::send", &*payload);
+                return -1i32;
+            }
+        };
+        if result.is_ok() || tx_state.stalled_since() != stalled_since {
+            arc_connection.touch();
+        }
+
+        match result {
+            Ok(()) => {
+                debug!("Cleared backlog");
+                if let Some(reason) = tx_state.pending_close.take() {
+                    debug!("Closing connection after flush");
+                    arc_connection.close_with(reason);
+                    return -1i32;
+                }
+                relieved = tx_state.relieve_backpressure();
+                flags = 0i32;
+                err = None;
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock {
+                    debug!("Backlog still not cleared, returning EPOLLOUT flags for fd");
+                    relieved = tx_state.relieve_backpressure();
+                    flags = libc::EPOLLOUT;
+                    err = None;
+                } else {
+                    relieved = false;
+                    flags = -1i32;
+                    err = Some(e);
+                }
+            }
+        }
+    } // Mutex unlock
+
+    if let Some(err) = err {
+        // The sender was told this data was queued, this is the only place it learns otherwise
+        let id = arc_connection.id;
+        let _ = call_handler(&handler, Some(id), "on_send_error", || {
+            handler.on_send_error(id, Error::new(err.kind(), err.to_string()))
+        });
+
+        { // Mutex lock
+            let mut err_state = match arc_connection.err_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            *err_state = Some(err);
+        } // Mutex unlock
+
+        arc_connection.epoll.wake();
+
+        return flags;
+    }
+
+    if relieved {
+        debug!("Connection {} drained to its low watermark", arc_connection.id);
+        let hydrogen_socket = HydrogenSocket::new(arc_connection.clone());
+        let result = call_handler(&handler, Some(arc_connection.id), "on_writable", || {
+            handler.on_writable(hydrogen_socket)
+        });
+        if let Err((callback, msg)) = result {
+            arc_connection.close_with(panic_error(callback, msg));
+            return -1i32;
+        }
+    }
+
+    flags
+}
+
+fn handle_read_event(arc_connection: Arc<Connection>, handler: EventHandler) -> i32 {
+    trace!("Handling read event");
+    arc_connection.first_byte_received.store(true, Ordering::Relaxed);
+    arc_connection.touch();
+
+    // Attempt recv, without holding the stream while the handler runs
+    let result = { // Mutex lock
+        let mut stream = match arc_connection.stream.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        // The fd is only closed with the stream held, once the connection is error'd, so it
+        // may already have been closed, and even reused
+        if arc_connection.is_errored() {
+            return -1i32;
+        }
+
+        match panic::catch_unwind(AssertUnwindSafe(|| stream.recv())) {
+            Ok(result) => result,
+            Err(payload) => {
+                drop(stream);
+                close_panicked(&arc_connection, "Stream::recv", &*payload);
+                return -1i32;
+            }
+        }
+    }; // Mutex unlock
+
+    match result {
+        Ok(mut queue) => {
+            trace!("Read {} msgs", queue.len());
+            for msg in queue.drain(..) {
+                let hydrogen_socket = HydrogenSocket::new(arc_connection.clone());
+                let id = Some(arc_connection.id);
+                let result = call_handler(&handler, id, "on_data_received", || {
+                    handler.on_data_received(hydrogen_socket, msg)
+                });
+                if let Err((callback, msg)) = result {
+                    // Nothing more is read, so the rest of this batch is dropped
+                    arc_connection.close_with(panic_error(callback, msg));
+                    return -1i32;
+                }
+            }
+            return libc::EPOLLIN;
+        }
+
+        Err(err) => {
+            let kind = err.kind();
+            if kind == ErrorKind::WouldBlock {
+                trace!("ErrorKind::WouldBlock");
+                return libc::EPOLLIN;
+            }
+
+            if kind != ErrorKind::UnexpectedEof
+                && kind != ErrorKind::ConnectionReset
+                && kind != ErrorKind::ConnectionAborted
+            {
+                error!("Unexpected during recv:   {}", err);
+            } else {
+                debug!("Received during read:    {}", err);
+            }
+
+            { // Mutex lock
+                // If we're in a state of ShouldClose, no need to worry
+                // about any other operations...
+                let mut err_state = match arc_connection.err_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                *err_state = Some(err);
+            } // Mutex unlock
+
+            arc_connection.epoll.wake();
+        }
+    };
+
+    -1i32
+}
+
+/// Makes a handler callback, catching any panic so it never unwinds into the server's threads.
+///
+/// A panic is reported through `Handler::on_handler_panic`, then returned as the name of the
+/// callback and the panic's message, leaving the caller to deal with the connection involved.
+pub fn call_handler<F, R>(handler: &EventHandler,
+                          id: Option<ConnectionId>,
+                          callback: &'static str,
+                          f: F)
+                          -> Result<R, (&'static str, String)>
+    where F: FnOnce() -> R
+{
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => return Ok(ret),
+        Err(payload) => payload
+    };
+
+    Err((callback, report_panic(handler, id, callback, &*payload)))
+}
+
+/// Removes a connection whose `Stream` panicked in `method`. The panic is reported as a handler
+/// panic would be, and its message returned.
+///
+/// Must not be called holding the connection's tx_mutex or stream, which the handler may use.
+pub fn close_panicked(arc_connection: &Connection,
+                      method: &'static str,
+                      payload: &(dyn Any + Send))
+                      -> String
+{
+    let handler = &arc_connection.handler;
+    let msg = report_panic(handler, Some(arc_connection.id), method, payload);
+    arc_connection.close_with(panic_error(method, msg.clone()));
+    msg
+}
+
+/// Reports a panic caught in `callback` through `Handler::on_handler_panic`, and returns the
+/// panic's message.
+fn report_panic(handler: &EventHandler,
+                id: Option<ConnectionId>,
+                callback: &'static str,
+                payload: &(dyn Any + Send))
+                -> String
+{
+    let msg = panic_message(payload);
+    error!("Handler panicked in {}: {}", callback, msg);
+
+    let err = HydrogenError::HandlerPanic(callback, msg.clone());
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handler.on_handler_panic(id, err)));
+    if result.is_err() {
+        error!("Handler panicked in on_handler_panic");
+    }
+
+    msg
+}
+
+/// Returns the message a panic was started with, if it was started with one.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Returns the reason a connection is removed with after a callback panicked handling it.
+pub fn panic_error(callback: &'static str, msg: String) -> Error {
+    Error::other(HydrogenError::HandlerPanic(callback, msg))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, mem, ptr};
+    use std::borrow::Cow;
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::mem::ManuallyDrop;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use config::ListenerConfig;
+    use super::*;
+
+    /// Stream writing straight to, and reading straight from, an accepted fd
+    struct RawStream {
+        fd: RawFd
+    }
+
+    impl AsRawFd for RawStream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd
+        }
+    }
+
+    impl Stream for RawStream {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            let mut msgs = Vec::<Vec<u8>>::new();
+            loop {
+                let mut buf = [0u8; 1024];
+                let num_read = unsafe {
+                    libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+                };
+                if num_read == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+                }
+                if num_read < 0 {
+                    let err = Error::last_os_error();
+                    if err.kind() == ErrorKind::WouldBlock && !msgs.is_empty() {
+                        return Ok(msgs);
+                    }
+                    return Err(err);
+                }
+                msgs.push(buf[..num_read as usize].to_vec());
+            }
+        }
+
+        fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            let num_written = unsafe {
+                libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len())
+            };
+            if num_written < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(num_written as usize)
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Echoes everything back prefixed with `name`, and records its listening socket
+    struct Echo {
+        name: &'static [u8],
+        listener_fd: Arc<Mutex<Option<RawFd>>>
+    }
+
+    impl Handler for Echo {
+        type Context = ();
+
+        fn on_server_created(&self, _: ListenerId, fd: RawFd) {
+            *self.listener_fd.lock().unwrap() = Some(fd);
+        }
+
+        fn on_new_connection(&self, _: ConnectionId, _: ListenerId, fd: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            (Box::new(RawStream { fd }), ())
+        }
+
+        fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>) {
+            let _ = socket.send(&[self.name, &buf[..]].concat());
+        }
+
+        fn on_connection_removed(&self, _: ConnectionId, _: Error) { }
+    }
+
+    fn tcp_listener(id: u32, port: u16) -> ListenerConfig {
+        ListenerConfig {
+            id: ListenerId(id),
+            addr: ListenAddr::Tcp { addr: "127.0.0.1".to_string(), port, v6_only: None }
+        }
+    }
+
+    fn config(listeners: Vec<ListenerConfig>) -> Config {
+        Config {
+            listeners,
+            max_threads: 2,
+            reactors: 2,
+            pre_allocated: 8,
+            tx_high_watermark: 1 << 20,
+            tx_low_watermark: 1 << 10,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            write_stall_timeout: None,
+            handoff_path: None
+        }
+    }
+
+    /// Returns the port of a bound TCP listening socket, without taking ownership of it
+    fn local_port(fd: RawFd) -> u16 {
+        let listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+        listener.local_addr().unwrap().port()
+    }
+
+    fn begin_echo(name: &'static [u8]) -> (ServerHandle, u16) {
+        let listener_fd = Arc::new(Mutex::new(None));
+        let handler = Box::new(Echo { name, listener_fd: listener_fd.clone() });
+        let server = begin(handler, config(vec![tcp_listener(0, 0)])).unwrap();
+
+        // Only bound once begin returns, the port 0 asked for is picked then
+        let fd = listener_fd.lock().unwrap().unwrap();
+        (server, local_port(fd))
+    }
+
+    fn connect(port: u16) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    /// Everything reported to a `Recorder`
+    #[derive(Default)]
+    struct Log {
+        listeners: Mutex<Vec<(ListenerId, RawFd)>>,
+        accepted: Mutex<Vec<(ConnectionId, ListenerId, RawFd)>>,
+        /// Threads `on_new_connection` was called on, one per listening socket
+        acceptors: Mutex<Vec<thread::ThreadId>>,
+        removed: Mutex<Vec<(ConnectionId, String)>>,
+        connects: Mutex<Vec<(ConnectionId, Result<(), ErrorKind>)>>,
+        panics: Mutex<Vec<String>>
+    }
+
+    impl Log {
+        fn removed_ids(&self) -> Vec<ConnectionId> {
+            self.removed.lock().unwrap().iter().map(|&(id, _)| id).collect()
+        }
+    }
+
+    /// Records what it is told, and hands everything received to `on_data`
+    struct Recorder {
+        log: Arc<Log>,
+        on_data: Box<dyn Fn(HydrogenSocket, Vec<u8>) + Send + Sync>
+    }
+
+    impl Handler for Recorder {
+        type Context = ();
+
+        fn on_server_created(&self, listener: ListenerId, fd: RawFd) {
+            self.log.listeners.lock().unwrap().push((listener, fd));
+        }
+
+        fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            self.log.accepted.lock().unwrap().push((id, listener, fd));
+            self.log.acceptors.lock().unwrap().push(thread::current().id());
+            (Box::new(RawStream { fd }), ())
+        }
+
+        fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>) {
+            (self.on_data)(socket, buf)
+        }
+
+        fn on_connection_removed(&self, id: ConnectionId, err: Error) {
+            self.log.removed.lock().unwrap().push((id, err.to_string()));
+        }
+
+        fn on_connect_result(&self, id: ConnectionId, result: Result<HydrogenSocket, Error>) {
+            let result = result.map(|_| ()).map_err(|err| err.kind());
+            self.log.connects.lock().unwrap().push((id, result));
+        }
+
+        fn on_handler_panic(&self, _: Option<ConnectionId>, err: HydrogenError) {
+            self.log.panics.lock().unwrap().push(err.to_string());
+        }
+    }
+
+    fn begin_recorder<F>(cfg: Config, on_data: F) -> (ServerHandle, Arc<Log>)
+        where F: Fn(HydrogenSocket, Vec<u8>) + Send + Sync + 'static
+    {
+        let log = Arc::new(Log::default());
+        let handler = Box::new(Recorder { log: log.clone(), on_data: Box::new(on_data) });
+        (begin(handler, cfg).unwrap(), log)
+    }
+
+    /// Returns the port of the first TCP listening socket the server reported
+    fn port_of(log: &Log) -> u16 {
+        local_port(log.listeners.lock().unwrap()[0].1)
+    }
+
+    /// Polls `done` until it returns true, failing the test after 5 seconds
+    fn wait_for<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "Timed out waiting");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn round_trip(port: u16, msg: &[u8]) -> Vec<u8> {
+        let mut stream = connect(port);
+        stream.write_all(msg).unwrap();
+
+        let mut reply = vec![0u8; msg.len() + 1];
+        stream.read_exact(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn servers_in_one_process_run_independently() {
+        let (first, first_port) = begin_echo(b"1");
+        let (second, second_port) = begin_echo(b"2");
+        assert!(first_port != second_port);
+
+        for _ in 0..4 {
+            assert_eq!(round_trip(first_port, b"ping"), b"1ping");
+            assert_eq!(round_trip(second_port, b"ping"), b"2ping");
+        }
+
+        // Shutting one down leaves the other's reactors running
+        first.shutdown();
+        first.join();
+        assert!(TcpStream::connect(("127.0.0.1", first_port)).is_err());
+        assert_eq!(round_trip(second_port, b"pong"), b"2pong");
+
+        second.shutdown();
+        second.join();
+    }
+
+    #[test]
+    fn shutdown_closes_connections_and_join_returns() {
+        let (server, port) = begin_echo(b"1");
+        let mut stream = connect(port);
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).unwrap();
+
+        // Every clone of the handle is able to wait on the same server
+        let joiner = {
+            let server = server.clone();
+            thread::spawn(move || server.join())
+        };
+        server.shutdown();
+        server.join();
+        joiner.join().unwrap();
+
+        assert_eq!(stream.read(&mut reply).unwrap(), 0);
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+
+        // Once stopped, there is nothing left to wait on
+        server.join();
+    }
+
+    #[test]
+    fn bind_failure_is_returned() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let handler = Box::new(Echo { name: b"1", listener_fd: Arc::new(Mutex::new(None)) });
+        match begin(handler, config(vec![tcp_listener(0, port)])) {
+            Err(HydrogenError::Bind(err)) => assert_eq!(err.kind(), ErrorKind::AddrInUse),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Bound a port already in use")
+        }
+    }
+
+    #[test]
+    fn invalid_config_is_returned() {
+        let handler = Box::new(Echo { name: b"1", listener_fd: Arc::new(Mutex::new(None)) });
+        let mut cfg = config(vec![tcp_listener(0, 0)]);
+        cfg.reactors = 0;
+        match begin(handler, cfg) {
+            Err(HydrogenError::InvalidConfig(_)) => { }
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Started without any reactors")
+        }
+    }
+
+    extern "C" fn ignore_signal(_: libc::c_int) { }
+
+    #[test]
+    fn interrupted_epoll_wait_is_retried() {
+        let (server, port) = begin_echo(b"1");
+
+        // Without SA_RESTART, a handled signal fails a blocked epoll_wait with EINTR
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = ignore_signal as *const () as libc::sighandler_t;
+        assert_eq!(unsafe { libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()) }, 0);
+
+        for _ in 0..10 {
+            for entry in fs::read_dir("/proc/self/task").unwrap() {
+                let path = entry.unwrap().path();
+                let name = fs::read_to_string(path.join("comm")).unwrap_or_default();
+                if !name.starts_with("Event Loop") {
+                    continue;
+                }
+                let tid: libc::pid_t = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+                unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, libc::SIGUSR1); }
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(round_trip(port, b"ping"), b"1ping");
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn connection_ids_are_never_reused() {
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), |socket, _| {
+            let _ = socket.send(&socket.id().0.to_le_bytes());
+        });
+        let port = port_of(&log);
+
+        // Each connection is closed before the next, so its fd is free to be reused
+        let mut replied = Vec::<ConnectionId>::new();
+        for num_removed in 1..4 {
+            let mut stream = connect(port);
+            stream.write_all(b"id?").unwrap();
+            let mut reply = [0u8; 8];
+            stream.read_exact(&mut reply).unwrap();
+            replied.push(ConnectionId(u64::from_le_bytes(reply)));
+
+            drop(stream);
+            wait_for(|| log.removed.lock().unwrap().len() == num_removed);
+        }
+
+        let accepted: Vec<ConnectionId> = log.accepted.lock().unwrap().iter()
+            .map(|&(id, _, _)| id)
+            .collect();
+        assert_eq!(accepted, replied);
+        assert_eq!(log.removed_ids(), replied);
+        assert!(replied[0] < replied[1] && replied[1] < replied[2]);
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn sockets_are_found_by_id_outside_callbacks() {
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), |_, _| { });
+        let mut stream = connect(port_of(&log));
+        wait_for(|| !log.accepted.lock().unwrap().is_empty());
+        let id = log.accepted.lock().unwrap()[0].0;
+
+        // Accepted connections reach their reactor's slab shortly after on_new_connection
+        wait_for(|| server.socket(id).is_some());
+        let socket = server.socket(id).unwrap();
+        assert_eq!(socket.id(), id);
+        let ids: Vec<ConnectionId> = server.connections().map(|socket| socket.id()).collect();
+        assert_eq!(ids, vec![id]);
+
+        // Sent from the test's own thread, outside any callback
+        assert!(matches!(socket.send(b"hello"), Ok(SendStatus::Written)));
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+
+        drop(stream);
+        wait_for(|| log.removed_ids() == vec![id]);
+        assert!(server.socket(id).is_none());
+        assert_eq!(server.connections().count(), 0);
+        assert!(matches!(socket.send(b"late"), Err(HydrogenError::ConnectionClosed)));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn broadcast_reaches_group_members_until_removed() {
+        // A single listening socket accepts in the order connected
+        let mut cfg = config(vec![tcp_listener(0, 0)]);
+        cfg.reactors = 1;
+        let (server, log) = begin_recorder(cfg, |_, _| { });
+        let port = port_of(&log);
+        let mut streams: Vec<TcpStream> = (0..3).map(|_| connect(port)).collect();
+        wait_for(|| log.accepted.lock().unwrap().len() == 3);
+        let ids: Vec<ConnectionId> = log.accepted.lock().unwrap().iter()
+            .map(|&(id, _, _)| id)
+            .collect();
+        wait_for(|| ids.iter().all(|&id| server.socket(id).is_some()));
+
+        // The last stream is the one left out
+        let groups = server.groups();
+        assert!(groups.join("room", ids[0]));
+        assert!(groups.join("room", ids[1]));
+        assert!(!groups.join("room", ConnectionId(u64::MAX)));
+        assert!(groups.broadcast("room", b"hi").is_empty());
+        for stream in streams[..2].iter_mut() {
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hi");
+        }
+        streams[2].set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(streams[2].read(&mut [0u8; 2]).is_err());
+
+        // A removed connection leaves its groups without being told to
+        drop(streams.remove(0));
+        wait_for(|| log.removed_ids() == vec![ids[0]]);
+        assert_eq!(groups.members("room"), vec![ids[1]]);
+
+        groups.leave("room", ids[1]);
+        assert!(groups.members("room").is_empty());
+        assert!(groups.broadcast("room", b"hi").is_empty());
+
+        server.shutdown();
+        server.join();
+    }
+
+    /// Stream whose peer is never able to take anything written to it
+    struct BrokenPipe {
+        inner: RawStream
+    }
+
+    impl AsRawFd for BrokenPipe {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.fd
+        }
+    }
+
+    impl Stream for BrokenPipe {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            self.inner.recv()
+        }
+
+        fn send(&mut self, _: &[u8]) -> Result<usize, Error> {
+            Err(Error::new(ErrorKind::BrokenPipe, "Broken pipe"))
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Replies to everything received over a `BrokenPipe`, twice, and records the outcomes
+    #[derive(Default)]
+    struct SendResults {
+        listener_fd: Mutex<Option<RawFd>>,
+        results: Mutex<Vec<Result<SendStatus, HydrogenError>>>,
+        removed: Mutex<Vec<ErrorKind>>
+    }
+
+    impl Handler for Arc<SendResults> {
+        type Context = ();
+
+        fn on_server_created(&self, _: ListenerId, fd: RawFd) {
+            *self.listener_fd.lock().unwrap() = Some(fd);
+        }
+
+        fn on_new_connection(&self, _: ConnectionId, _: ListenerId, fd: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            (Box::new(BrokenPipe { inner: RawStream { fd } }), ())
+        }
+
+        fn on_data_received(&self, socket: HydrogenSocket, _: Vec<u8>) {
+            let mut results = self.results.lock().unwrap();
+            results.push(socket.send(b"first"));
+            results.push(socket.send(b"second"));
+        }
+
+        fn on_connection_removed(&self, _: ConnectionId, err: Error) {
+            self.removed.lock().unwrap().push(err.kind());
+        }
+    }
+
+    #[test]
+    fn failed_send_is_returned_and_removes_connection() {
+        let recorded = Arc::new(SendResults::default());
+        let server = begin(Box::new(recorded.clone()), config(vec![tcp_listener(0, 0)])).unwrap();
+        let mut stream = connect(local_port(recorded.listener_fd.lock().unwrap().unwrap()));
+        stream.write_all(b"go").unwrap();
+        wait_for(|| !recorded.removed.lock().unwrap().is_empty());
+
+        {
+            let results = recorded.results.lock().unwrap();
+            match results[0] {
+                Err(HydrogenError::Io(ref err)) => assert_eq!(err.kind(), ErrorKind::BrokenPipe),
+                ref other => panic!("Unexpected first send: {:?}", other)
+            }
+            assert!(matches!(results[1], Err(HydrogenError::ConnectionClosed)));
+        }
+        assert_eq!(*recorded.removed.lock().unwrap(), vec![ErrorKind::BrokenPipe]);
+        assert_eq!(stream.read(&mut [0u8; 8]).unwrap(), 0);
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn close_after_flush_sends_queued_data_first() {
+        const LEN: usize = 8 << 20;
+
+        let mut cfg = config(vec![tcp_listener(0, 0)]);
+        cfg.tx_high_watermark = 2 * LEN;
+        cfg.tx_low_watermark = LEN;
+        let sent = Arc::new(Mutex::new(None));
+        let (server, log) = {
+            let sent = sent.clone();
+            begin_recorder(cfg, move |socket, _| {
+                let status = socket.send(&vec![7u8; LEN]);
+                socket.close_after_flush(Error::other("Done sending"));
+                assert!(matches!(socket.send(b"more"), Err(HydrogenError::ConnectionClosed)));
+                *sent.lock().unwrap() = Some(status.unwrap());
+            })
+        };
+        let mut stream = connect(port_of(&log));
+        stream.write_all(b"go").unwrap();
+
+        // Nothing is read until the handler is done, so most of it had to be queued
+        wait_for(|| sent.lock().unwrap().is_some());
+        assert_eq!(sent.lock().unwrap().unwrap(), SendStatus::Queued);
+
+        let mut received = Vec::<u8>::new();
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), LEN);
+        assert!(received.iter().all(|&b| b == 7));
+        wait_for(|| !log.removed.lock().unwrap().is_empty());
+        assert_eq!(log.removed.lock().unwrap()[0].1, "Done sending");
+        assert!(log.panics.lock().unwrap().is_empty());
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn close_removes_connection_with_reason() {
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), |socket, _| {
+            socket.close(Error::other("Closed by handler"));
+        });
+        let mut stream = connect(port_of(&log));
+        stream.write_all(b"go").unwrap();
+
+        assert_eq!(stream.read(&mut [0u8; 8]).unwrap(), 0);
+        wait_for(|| !log.removed.lock().unwrap().is_empty());
+        assert_eq!(log.removed.lock().unwrap()[0].1, "Closed by handler");
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn socket_timers_run_until_cancelled_or_removed() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let (server, log) = {
+            let ticks = ticks.clone();
+            begin_recorder(config(vec![tcp_listener(0, 0)]), move |socket, _| {
+                socket.schedule(Duration::from_millis(5), |socket| {
+                    let _ = socket.send(b"cancelled");
+                }).cancel();
+                socket.schedule(Duration::from_millis(20), |socket| {
+                    let _ = socket.send(b"later");
+                });
+                let ticks = ticks.clone();
+                socket.schedule_repeating(Duration::from_millis(5), move |_| {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        };
+        let mut stream = connect(port_of(&log));
+        stream.write_all(b"go").unwrap();
+
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"later");
+        stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(stream.read(&mut [0u8; 16]).is_err());
+        wait_for(|| ticks.load(Ordering::SeqCst) >= 3);
+
+        // Removing the connection cancels its repeating timer, a run already underway aside
+        drop(stream);
+        wait_for(|| !log.removed.lock().unwrap().is_empty());
+        thread::sleep(Duration::from_millis(20));
+        let num_ticks = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(ticks.load(Ordering::SeqCst), num_ticks);
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn server_timers_run_until_cancelled() {
+        let (server, _) = begin_recorder(config(vec![tcp_listener(0, 0)]), |_, _| { });
+        let (tx, rx) = mpsc::channel();
+        let (cancelled_tx, cancelled_rx) = mpsc::channel::<()>();
+
+        server.schedule(Duration::from_millis(10), move || tx.send(()).unwrap());
+        server.schedule(Duration::from_millis(5), move || cancelled_tx.send(()).unwrap()).cancel();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(cancelled_rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        let (tx, rx) = mpsc::channel();
+        let repeating = server.schedule_repeating(Duration::from_millis(5), move || {
+            let _ = tx.send(());
+        });
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        repeating.cancel();
+        assert!(repeating.is_cancelled());
+
+        server.shutdown();
+        server.join();
+    }
+
+    /// State kept for each connection accepted by `Counter`
+    struct Session {
+        id: ConnectionId,
+        received: AtomicUsize
+    }
+
+    /// Replies to every message with its connection's id and how many messages it has sent
+    struct Counter {
+        listener_fd: Arc<Mutex<Option<RawFd>>>
+    }
+
+    impl Handler for Counter {
+        type Context = Session;
+
+        fn on_server_created(&self, _: ListenerId, fd: RawFd) {
+            *self.listener_fd.lock().unwrap() = Some(fd);
+        }
+
+        fn on_new_connection(&self, id: ConnectionId, _: ListenerId, fd: RawFd)
+            -> (Box<dyn Stream>, Session)
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            (Box::new(RawStream { fd }), Session { id, received: AtomicUsize::new(0) })
+        }
+
+        fn on_data_received(&self, socket: HydrogenSocket<Session>, _: Vec<u8>) {
+            let session = socket.context();
+            let received = session.received.fetch_add(1, Ordering::SeqCst) + 1;
+            let _ = socket.send(&[&session.id.0.to_le_bytes()[..], &[received as u8]].concat());
+        }
+
+        fn on_connection_removed(&self, _: ConnectionId, _: Error) { }
+    }
+
+    #[test]
+    fn context_is_kept_per_connection() {
+        let listener_fd = Arc::new(Mutex::new(None));
+        let handler = Box::new(Counter { listener_fd: listener_fd.clone() });
+        let server = begin(handler, config(vec![tcp_listener(0, 0)])).unwrap();
+        let port = local_port(listener_fd.lock().unwrap().unwrap());
+        let mut streams = [connect(port), connect(port)];
+
+        let mut ids = [ConnectionId(0); 2];
+        for round in 1..4 {
+            for (i, stream) in streams.iter_mut().enumerate() {
+                stream.write_all(b"ping").unwrap();
+                let mut reply = [0u8; 9];
+                stream.read_exact(&mut reply).unwrap();
+                assert_eq!(reply[8], round);
+                let mut id = [0u8; 8];
+                id.copy_from_slice(&reply[..8]);
+                ids[i] = ConnectionId(u64::from_le_bytes(id));
+            }
+        }
+        assert!(ids[0] != ids[1]);
+
+        // The same context is reachable through the server handle
+        for &id in ids.iter() {
+            let socket = server.socket(id).unwrap();
+            assert_eq!(socket.context().id, id);
+            assert_eq!(socket.context().received.load(Ordering::SeqCst), 3);
+        }
+
+        server.shutdown();
+        server.join();
+    }
+
+    fn assert_send_sync<T: Send + Sync>() { }
+
+    #[test]
+    fn handles_and_sockets_are_send_and_sync() {
+        assert_send_sync::<ServerHandle>();
+        assert_send_sync::<HydrogenSocket>();
+        assert_send_sync::<ServerHandle<Session>>();
+        assert_send_sync::<HydrogenSocket<Session>>();
+        assert_send_sync::<Box<dyn Handler<Context = ()>>>();
+    }
+
+    #[test]
+    fn handler_state_is_shared_across_threads() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let (server, log) = {
+            let received = received.clone();
+            begin_recorder(config(vec![tcp_listener(0, 0)]), move |socket, buf| {
+                received.fetch_add(buf.len(), Ordering::SeqCst);
+                let _ = socket.send(&buf);
+            })
+        };
+        let port = port_of(&log);
+
+        // Every client waits on each echo, so no two messages from one client are coalesced
+        let clients: Vec<_> = (0..8).map(|_| thread::spawn(move || {
+            let mut stream = connect(port);
+            for _ in 0..100 {
+                stream.write_all(b"ping").unwrap();
+                let mut reply = [0u8; 4];
+                stream.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply, b"ping");
+            }
+        })).collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 8 * 100 * 4);
+
+        server.shutdown();
+        server.join();
+    }
+
+    /// Stream panicking in whichever method a message names
+    struct Cued {
+        inner: RawStream
+    }
+
+    impl AsRawFd for Cued {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.fd
+        }
+    }
+
+    impl Stream for Cued {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            let msgs = self.inner.recv()?;
+            if msgs.iter().any(|msg| msg == b"recv") {
+                panic!("recv cue");
+            }
+            Ok(msgs)
+        }
+
+        fn encode<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+            if buf == b"encode" {
+                panic!("encode cue");
+            }
+            Cow::Borrowed(buf)
+        }
+
+        fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if buf == b"send" {
+                panic!("send cue");
+            }
+            self.inner.send(buf)
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Echoes everything over a `Cued` stream, panicking itself on "data"
+    struct PanicOnCue {
+        log: Arc<Log>
+    }
+
+    impl Handler for PanicOnCue {
+        type Context = ();
+
+        fn on_server_created(&self, listener: ListenerId, fd: RawFd) {
+            self.log.listeners.lock().unwrap().push((listener, fd));
+        }
+
+        fn on_new_connection(&self, _: ConnectionId, _: ListenerId, fd: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+            (Box::new(Cued { inner: RawStream { fd } }), ())
+        }
+
+        fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>) {
+            if buf == b"data" {
+                panic!("data cue");
+            }
+            let _ = socket.send(&buf);
+        }
+
+        fn on_connection_removed(&self, id: ConnectionId, err: Error) {
+            self.log.removed.lock().unwrap().push((id, err.to_string()));
+        }
+
+        fn on_handler_panic(&self, _: Option<ConnectionId>, err: HydrogenError) {
+            self.log.panics.lock().unwrap().push(err.to_string());
+        }
+    }
+
+    #[test]
+    fn panics_remove_only_their_own_connection() {
+        // With a single pool thread, one lost to a panic would stall every connection
+        let mut cfg = config(vec![tcp_listener(0, 0)]);
+        cfg.max_threads = 1;
+        let log = Arc::new(Log::default());
+        let server = begin(Box::new(PanicOnCue { log: log.clone() }), cfg).unwrap();
+        let port = port_of(&log);
+        let mut survivor = connect(port);
+
+        let cues: [&[u8]; 4] = [b"data", b"recv", b"encode", b"send"];
+        for (num_removed, cue) in cues.iter().enumerate() {
+            let mut stream = connect(port);
+            stream.write_all(cue).unwrap();
+            assert_eq!(stream.read(&mut [0u8; 8]).unwrap(), 0);
+            wait_for(|| log.removed.lock().unwrap().len() == num_removed + 1);
+
+            survivor.write_all(b"ping").unwrap();
+            let mut reply = [0u8; 4];
+            survivor.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"ping");
+        }
+
+        let expected = vec![
+            "Handler panicked in on_data_received: data cue",
+            "Handler panicked in Stream::recv: recv cue",
+            "Handler panicked in Stream::send: encode cue",
+            "Handler panicked in Stream::send: send cue"
+        ];
+        assert_eq!(*log.panics.lock().unwrap(), expected);
+        let reasons: Vec<String> = log.removed.lock().unwrap().iter()
+            .map(|(_, reason)| reason.clone())
+            .collect();
+        assert_eq!(reasons, expected);
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn connections_are_tagged_with_their_listener() {
+        let mut cfg = config(vec![tcp_listener(1, 0), tcp_listener(7, 0), ListenerConfig {
+            id: ListenerId(9),
+            addr: ListenAddr::Tcp { addr: "::1".to_string(), port: 0, v6_only: Some(true) }
+        }]);
+        cfg.reactors = 1;
+        let (server, log) = begin_recorder(cfg, |_, _| { });
+
+        let listeners = log.listeners.lock().unwrap().clone();
+        let ids: Vec<ListenerId> = listeners.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, vec![ListenerId(1), ListenerId(7), ListenerId(9)]);
+
+        let mut streams = Vec::new();
+        for &(_, fd) in listeners.iter() {
+            let listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+            streams.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            wait_for(|| log.accepted.lock().unwrap().len() == streams.len());
+        }
+        let accepted: Vec<ListenerId> = log.accepted.lock().unwrap().iter()
+            .map(|&(_, listener, _)| listener)
+            .collect();
+        assert_eq!(accepted, ids);
+
+        // Only reachable over IPv6
+        let v6_port = local_port(listeners[2].1);
+        assert!(TcpStream::connect(("::1", v6_port)).is_ok());
+        assert!(TcpStream::connect(("127.0.0.1", v6_port)).is_err());
+
+        server.shutdown();
+        server.join();
+    }
+
+    fn unix_listener(path: &Path) -> ListenerConfig {
+        ListenerConfig {
+            id: ListenerId(0),
+            addr: ListenAddr::Unix { path: path.to_path_buf(), mode: Some(0o600) }
+        }
+    }
+
+    #[test]
+    fn unix_listener_reports_peer_credentials() {
+        let path = ::std::env::temp_dir()
+            .join(format!("hydrogen-test-{}-peer-credentials.sock", unsafe { libc::getpid() }));
+
+        // Left behind by a listener that is gone, as after a crash
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (server, log) = begin_recorder(config(vec![unix_listener(&path)]), |socket, _| {
+            let creds = socket.peer_credentials().unwrap();
+            let _ = socket.send(&[creds.pid.to_le_bytes(), creds.uid.to_le_bytes()].concat());
+        });
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"who?").unwrap();
+        let mut reply = [0u8; 8];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..4], &unsafe { libc::getpid() }.to_le_bytes());
+        assert_eq!(&reply[4..], &unsafe { libc::getuid() }.to_le_bytes());
+        assert_eq!(log.accepted.lock().unwrap().len(), 1);
+
+        // A socket file still being listened on is not stale
+        let handler = Box::new(Echo { name: b"2", listener_fd: Arc::new(Mutex::new(None)) });
+        match begin(handler, config(vec![unix_listener(&path)])) {
+            Err(HydrogenError::Bind(err)) => assert_eq!(err.kind(), ErrorKind::AddrInUse),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Bound a socket file already in use")
+        }
+        assert!(UnixStream::connect(&path).is_ok());
+
+        server.shutdown();
+        server.join();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reactors_share_a_port() {
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), |socket, buf| {
+            let _ = socket.send(&buf);
+        });
+
+        // One listening socket for each reactor, all bound to the same port
+        let listeners = log.listeners.lock().unwrap().clone();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners.iter().all(|&(id, _)| id == ListenerId(0)));
+        assert!(listeners[0].1 != listeners[1].1);
+        let port = local_port(listeners[0].1);
+        assert_eq!(local_port(listeners[1].1), port);
+
+        // The kernel spreads connections across the sockets by their source port
+        let num_acceptors = || {
+            log.acceptors.lock().unwrap().iter().collect::<HashSet<_>>().len()
+        };
+        let mut streams = Vec::new();
+        while num_acceptors() < 2 {
+            assert!(streams.len() < 64, "Every connection was accepted on one socket");
+            streams.push(connect(port));
+            wait_for(|| log.acceptors.lock().unwrap().len() == streams.len());
+        }
+
+        for stream in streams.iter_mut() {
+            stream.write_all(b"ping").unwrap();
+            let mut reply = [0u8; 4];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"ping");
+        }
+
+        server.shutdown();
+        server.join();
+    }
+
+    fn raw_stream(_: ConnectionId, fd: RawFd) -> (Box<dyn Stream>, ()) {
+        (Box::new(RawStream { fd }), ())
+    }
+
+    #[test]
+    fn connect_reports_success() {
+        let (target, target_port) = begin_echo(b"e");
+        let (tx, rx) = mpsc::channel();
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), move |_, buf| {
+            tx.send(buf).unwrap();
+        });
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], target_port));
+        let id = server.connect(addr, raw_stream).unwrap();
+
+        wait_for(|| !log.connects.lock().unwrap().is_empty());
+        assert_eq!(*log.connects.lock().unwrap(), vec![(id, Ok(()))]);
+        wait_for(|| server.socket(id).is_some());
+        assert!(server.socket(id).unwrap().send(b"hi").is_ok());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"ehi");
+
+        // From here on, it is removed as an accepted connection would be
+        target.shutdown();
+        target.join();
+        wait_for(|| log.removed_ids() == vec![id]);
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[test]
+    fn connect_reports_refusal() {
+        let (server, log) = begin_recorder(config(vec![tcp_listener(0, 0)]), |_, _| { });
+
+        // Nothing listens on a port just released
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        match server.connect(SocketAddr::from(([127, 0, 0, 1], port)), raw_stream) {
+            Ok(id) => {
+                wait_for(|| !log.connects.lock().unwrap().is_empty());
+                let refused = Err(ErrorKind::ConnectionRefused);
+                assert_eq!(*log.connects.lock().unwrap(), vec![(id, refused)]);
+            }
+            Err(HydrogenError::Connect(err)) => {
+                assert_eq!(err.kind(), ErrorKind::ConnectionRefused)
+            }
+            Err(err) => panic!("Unexpected error: {}", err)
+        }
+        assert_eq!(server.connections().count(), 0);
+        assert!(log.removed.lock().unwrap().is_empty());
+
+        server.shutdown();
+        server.join();
+    }
 }
@@ -0,0 +1,197 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+/// Stable handle to an element in a `Slab`.
+///
+/// The low 32 bits are the element's offset, the high 32 bits are the generation of that offset
+/// when the element was inserted. A token stays valid until its element is removed, and is never
+/// mistaken for a later element stored at the same offset. Tokens fit in the `u64` user data of
+/// an epoll event, which is how connections are found when epoll reports on them.
+pub type Token = u64;
+
+struct Entry<T> {
+    /// Bumped every time the element at this offset is removed
+    generation: u32,
+    /// The stored element, None when the offset is vacant
+    value: Option<T>
+}
+
+/// Pre-allocated chunk of memory with O(1) insertion, removal and lookup by `Token`.
+///
+/// Elements never move once inserted, so tokens remain valid across removals of other elements.
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    /// Vacant offsets, reused most recently vacated first
+    vacant: Vec<usize>,
+    num_elems: usize
+}
+
+impl<T> Slab<T> {
+    /// Creates a new Slab with initial room without re-allocation for `capacity` num elements.
+    pub fn new(capacity: usize) -> Slab<T> {
+        Slab {
+            entries: Vec::with_capacity(capacity),
+            vacant: Vec::new(),
+            num_elems: 0
+        }
+    }
+
+    /// Inserts the element returned from `f`, which is passed the token the element will be
+    /// stored under.
+    pub fn insert_with<F>(&mut self, f: F) -> Token
+        where F: FnOnce(Token) -> T
+    {
+        let offset = match self.vacant.pop() {
+            Some(offset) => offset,
+            None => {
+                self.entries.push(Entry { generation: 0, value: None });
+                self.entries.len() - 1
+            }
+        };
+
+        let token = make_token(offset, self.entries[offset].generation);
+        self.entries[offset].value = Some(f(token));
+        self.num_elems += 1;
+
+        token
+    }
+
+    /// Returns a reference to the element stored under `token`, if it is still present.
+    pub fn get(&self, token: Token) -> Option<&T> {
+        let (offset, generation) = split_token(token);
+        match self.entries.get(offset) {
+            Some(entry) if entry.generation == generation => entry.value.as_ref(),
+            _ => None
+        }
+    }
+
+    /// Removes and returns the element stored under `token`, if it is still present.
+    pub fn remove(&mut self, token: Token) -> Option<T> {
+        let (offset, generation) = split_token(token);
+        let value = match self.entries.get_mut(offset) {
+            Some(entry) if entry.generation == generation && entry.value.is_some() => {
+                entry.generation = entry.generation.wrapping_add(1);
+                entry.value.take()
+            }
+            _ => return None
+        };
+
+        self.vacant.push(offset);
+        self.num_elems -= 1;
+
+        value
+    }
+
+    /// Returns the number of elements in the slab.
+    pub fn len(&self) -> usize {
+        self.num_elems
+    }
+
+    /// Returns an iterator over the slab's elements and their tokens.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            entries: self.entries.iter().enumerate()
+        }
+    }
+}
+
+/// Iterator over the elements of a `Slab`, paired with their tokens.
+pub struct Iter<'a, T: 'a> {
+    entries: ::std::iter::Enumerate<::std::slice::Iter<'a, Entry<T>>>
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Token, &'a T);
+
+    fn next(&mut self) -> Option<(Token, &'a T)> {
+        for (offset, entry) in &mut self.entries {
+            if let Some(ref value) = entry.value {
+                return Some((make_token(offset, entry.generation), value));
+            }
+        }
+
+        None
+    }
+}
+
+fn make_token(offset: usize, generation: u32) -> Token {
+    ((generation as u64) << 32) | (offset as u64 & 0xffff_ffff)
+}
+
+fn split_token(token: Token) -> (usize, u32) {
+    ((token & 0xffff_ffff) as usize, (token >> 32) as u32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_offset_bumps_generation() {
+        let mut slab = Slab::<&str>::new(4);
+        let first = slab.insert_with(|_| "first");
+        assert_eq!(slab.remove(first), Some("first"));
+
+        let second = slab.insert_with(|_| "second");
+        assert_eq!(split_token(first).0, split_token(second).0);
+        assert_eq!(split_token(second).1, split_token(first).1 + 1);
+        assert_eq!(slab.get(second), Some(&"second"));
+    }
+
+    #[test]
+    fn stale_token_is_rejected() {
+        let mut slab = Slab::<&str>::new(4);
+        let stale = slab.insert_with(|_| "first");
+        slab.remove(stale);
+        let current = slab.insert_with(|_| "second");
+
+        assert_eq!(slab.get(stale), None);
+        assert_eq!(slab.remove(stale), None);
+        assert_eq!(slab.len(), 1);
+        assert_eq!(slab.get(current), Some(&"second"));
+    }
+
+    #[test]
+    fn remove_twice_returns_none() {
+        let mut slab = Slab::<u32>::new(1);
+        let token = slab.insert_with(|_| 7);
+
+        assert_eq!(slab.remove(token), Some(7));
+        assert_eq!(slab.remove(token), None);
+        assert_eq!(slab.len(), 0);
+    }
+
+    #[test]
+    fn insert_with_passes_its_own_token() {
+        let mut slab = Slab::<Token>::new(2);
+        let a = slab.insert_with(|token| token);
+        let b = slab.insert_with(|token| token);
+
+        assert_eq!(slab.get(a), Some(&a));
+        assert_eq!(slab.get(b), Some(&b));
+    }
+
+    #[test]
+    fn iter_skips_vacant_offsets() {
+        let mut slab = Slab::<u32>::new(3);
+        let a = slab.insert_with(|_| 1);
+        let b = slab.insert_with(|_| 2);
+        let c = slab.insert_with(|_| 3);
+        slab.remove(b);
+
+        let items: Vec<(Token, u32)> = slab.iter().map(|(token, value)| (token, *value)).collect();
+        assert_eq!(items, vec![(a, 1), (c, 3)]);
+    }
+
+    #[test]
+    fn token_out_of_range_is_rejected() {
+        let slab = Slab::<u32>::new(0);
+        assert_eq!(slab.get(make_token(5, 0)), None);
+    }
+}
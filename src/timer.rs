@@ -0,0 +1,551 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use slab::Token;
+use types::{Connection, EventHandler};
+use epoll::Epoll;
+use server;
+
+
+// Number of slots in the wheel
+const NUM_SLOTS: u64 = 512;
+
+// Resolution of the wheel. Deadlines are rounded up to the next tick.
+const TICK_MS: u64 = 10;
+
+struct Entry<T> {
+    /// Tick the entry expires on
+    tick: u64,
+    value: T
+}
+
+/// Hashed timing wheel, owned by the event loop.
+///
+/// Insertion is O(1). Advancing visits only the slots for the ticks that have passed, and each
+/// entry in those slots, so timers far in the future cost nothing until their slot comes around.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<Entry<T>>>,
+    /// Instant tick 0 started at
+    start: Instant,
+    /// Last tick that has been advanced through
+    current_tick: u64,
+    /// Earliest tick any entry expires on, when known
+    next_tick: Option<u64>,
+    num_entries: usize
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> TimerWheel<T> {
+        let mut slots = Vec::<Vec<Entry<T>>>::with_capacity(NUM_SLOTS as usize);
+        for _ in 0..NUM_SLOTS {
+            slots.push(Vec::new());
+        }
+
+        TimerWheel {
+            slots,
+            start: Instant::now(),
+            current_tick: 0,
+            next_tick: None,
+            num_entries: 0
+        }
+    }
+
+    /// Adds `value`, to be returned from `advance` once `deadline` has passed.
+    pub fn insert(&mut self, deadline: Instant, value: T) {
+        let tick = cmp::max(self.tick_at(deadline, true), self.current_tick + 1);
+        self.slots[(tick % NUM_SLOTS) as usize].push(Entry { tick, value });
+        self.num_entries += 1;
+
+        if self.num_entries == 1 || self.next_tick.is_some_and(|next| tick < next) {
+            self.next_tick = Some(tick);
+        }
+    }
+
+    /// Moves the wheel forward to `now`, pushing every expired value onto `expired`.
+    pub fn advance(&mut self, now: Instant, expired: &mut Vec<T>) {
+        let target_tick = self.tick_at(now, false);
+        if target_tick <= self.current_tick {
+            return;
+        }
+
+        // After a full turn every slot has been visited, however long it has been
+        let num_steps = cmp::min(target_tick - self.current_tick, NUM_SLOTS);
+        for step in 1..(num_steps + 1) {
+            let slot = &mut self.slots[((self.current_tick + step) % NUM_SLOTS) as usize];
+
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].tick <= target_tick {
+                    expired.push(slot.swap_remove(i).value);
+                    self.num_entries -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        self.current_tick = target_tick;
+        if self.next_tick.is_some_and(|next| next <= target_tick) {
+            self.next_tick = None;
+        }
+    }
+
+    /// Returns how long until the next entry expires, or None if the wheel is empty.
+    pub fn next_timeout(&mut self, now: Instant) -> Option<Duration> {
+        if self.num_entries == 0 {
+            return None;
+        }
+
+        let next_tick = match self.next_tick {
+            Some(tick) => tick,
+            None => {
+                let tick = self.find_next_tick();
+                self.next_tick = Some(tick);
+                tick
+            }
+        };
+
+        let deadline = self.start + Duration::from_millis(next_tick * TICK_MS);
+        if deadline > now {
+            Some(deadline - now)
+        } else {
+            Some(Duration::from_millis(0))
+        }
+    }
+
+    /// Scans forward from the current tick for the earliest entry.
+    fn find_next_tick(&self) -> u64 {
+        let mut earliest = u64::MAX;
+        for step in 1..(NUM_SLOTS + 1) {
+            let tick = self.current_tick + step;
+            for entry in self.slots[(tick % NUM_SLOTS) as usize].iter() {
+                earliest = cmp::min(earliest, entry.tick);
+            }
+
+            // Nothing in a later slot can expire before an entry due during this turn
+            if earliest <= tick {
+                break;
+            }
+        }
+
+        earliest
+    }
+
+    fn tick_at(&self, instant: Instant, round_up: bool) -> u64 {
+        let elapsed = if instant > self.start {
+            instant - self.start
+        } else {
+            Duration::from_millis(0)
+        };
+
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let tick = elapsed_ms / TICK_MS;
+        if round_up && (elapsed_ms % TICK_MS != 0 || elapsed.subsec_nanos() % 1_000_000 != 0) {
+            tick + 1
+        } else {
+            tick
+        }
+    }
+}
+
+/// Something the event loop's timer wheel is waiting on.
+pub enum TimerEvent {
+    /// The connection with this token should have its timeouts checked
+    TimeoutCheck(Token),
+    /// A consumer scheduled callback is due
+    Scheduled(Arc<ScheduledTimer>)
+}
+
+/// A consumer scheduled callback, shared between the event loop and its `TimerHandle`s.
+pub struct ScheduledTimer {
+    cancelled: AtomicBool,
+    /// Connection the timer is tied to, it is cancelled once the connection is removed
+    connection: Option<Arc<Connection>>,
+    /// Time between runs of a repeating timer
+    interval: Option<Duration>,
+    /// Taken out while running on the threadpool, dropped once the timer is finished
+    callback: Mutex<Option<Box<dyn FnMut() + Send>>>
+}
+
+impl ScheduledTimer {
+    pub fn new(connection: Option<Arc<Connection>>,
+               interval: Option<Duration>,
+               callback: Box<dyn FnMut() + Send>)
+               -> ScheduledTimer
+    {
+        ScheduledTimer {
+            cancelled: AtomicBool::new(false),
+            connection,
+            interval,
+            callback: Mutex::new(Some(callback))
+        }
+    }
+
+    /// Returns true if the timer should never run again.
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        match self.connection {
+            Some(ref arc_connection) => arc_connection.is_errored(),
+            None => false
+        }
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+
+        // Anything the callback holds on to is released now, rather than when it comes due
+        let callback = { // Mutex lock
+            let mut callback = match self.callback.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            callback.take()
+        }; // Mutex unlock
+        drop(callback);
+    }
+
+    /// Runs the callback, then hands a repeating timer back to the scheduler for its next run.
+    ///
+    /// A panic in the callback is reported to `handler`, and removes the timer's connection.
+    /// Called from the I/O threadpool.
+    pub fn run(timer: Arc<ScheduledTimer>, scheduler: &Scheduler, handler: &EventHandler) {
+        let callback = { // Mutex lock
+            let mut callback = match timer.callback.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            callback.take()
+        }; // Mutex unlock
+
+        let mut callback = match callback {
+            Some(callback) => callback,
+            None => return
+        };
+        let id = timer.connection.as_ref().map(|arc_connection| arc_connection.id);
+        let result = server::call_handler(handler, id, "scheduled timer", &mut callback);
+        if let Err((callback, msg)) = result {
+            if let Some(ref arc_connection) = timer.connection {
+                arc_connection.close_with(server::panic_error(callback, msg));
+            }
+        }
+
+        let interval = match timer.interval {
+            Some(interval) => interval,
+            None => return
+        };
+        if timer.is_cancelled() {
+            return;
+        }
+
+        { // Mutex lock
+            let mut slot = match timer.callback.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            *slot = Some(callback);
+        } // Mutex unlock
+
+        // The next run is measured from the end of this one, so runs never overlap
+        scheduler.schedule(Instant::now() + interval, timer);
+    }
+}
+
+/// Handle to a timer scheduled through `HydrogenSocket::schedule` or `ServerHandle::schedule`.
+///
+/// Dropping the handle does not cancel the timer.
+#[derive(Clone)]
+pub struct TimerHandle {
+    timer: Arc<ScheduledTimer>
+}
+
+impl TimerHandle {
+    pub fn new(timer: Arc<ScheduledTimer>) -> TimerHandle {
+        TimerHandle {
+            timer
+        }
+    }
+
+    /// Stops the timer from running again. A run already in progress is not interrupted.
+    pub fn cancel(&self) {
+        self.timer.cancel();
+    }
+
+    /// Returns true if the timer has been cancelled, or its connection has been removed.
+    pub fn is_cancelled(&self) -> bool {
+        self.timer.is_cancelled()
+    }
+}
+
+/// Timers waiting to be added to the event loop's wheel, with their deadlines.
+type PendingTimers = Arc<Mutex<Vec<(Instant, Arc<ScheduledTimer>)>>>;
+
+/// Hands timers to an event loop, the only thread that touches its wheel.
+#[derive(Clone)]
+pub struct Scheduler {
+    pending: PendingTimers,
+    /// Epoll instance the event loop waits on, woken whenever a timer is handed over
+    epoll: Arc<Epoll>
+}
+
+impl Scheduler {
+    pub fn new(epoll: Arc<Epoll>) -> Scheduler {
+        Scheduler {
+            pending: Arc::new(Mutex::new(Vec::new())),
+            epoll
+        }
+    }
+
+    /// Hands `timer` to the event loop, to run once `deadline` has passed.
+    pub fn schedule(&self, deadline: Instant, timer: Arc<ScheduledTimer>) {
+        { // Mutex lock
+            let mut pending = match self.pending.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            pending.push((deadline, timer));
+        } // Mutex unlock
+
+        self.epoll.wake();
+    }
+
+    /// Hands a new timer to the event loop, to first run after `after`.
+    pub fn schedule_timer(&self, after: Duration, timer: ScheduledTimer) -> TimerHandle {
+        let timer = Arc::new(timer);
+        self.schedule(Instant::now() + after, timer.clone());
+
+        TimerHandle::new(timer)
+    }
+
+    /// Moves every pending timer into `timers`.
+    pub fn drain_into(&self, timers: &mut TimerWheel<TimerEvent>) {
+        let mut pending = match self.pending.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        for (deadline, timer) in pending.drain(..) {
+            timers.insert(deadline, TimerEvent::Scheduled(timer));
+        }
+    }
+
+    /// Drops every pending timer. Used during shutdown, as timers tied to a connection keep it
+    /// alive, and the connection keeps the scheduler alive.
+    pub fn clear(&self) {
+        let mut pending = match self.pending.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        pending.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use epoll::Epoll;
+    use types::{ConnectionId, HydrogenSocket, ListenerId};
+    use {Error, Handler, Stream};
+    use super::*;
+
+    /// Handler that only records the panics reported to it
+    #[derive(Default)]
+    struct Panics {
+        reported: Mutex<Vec<String>>
+    }
+
+    impl Handler for Panics {
+        type Context = ();
+
+        fn on_server_created(&self, _: ListenerId, _: RawFd) { }
+        fn on_new_connection(&self, _: ConnectionId, _: ListenerId, _: RawFd)
+            -> (Box<dyn Stream>, ())
+        {
+            unreachable!()
+        }
+        fn on_data_received(&self, _: HydrogenSocket, _: Vec<u8>) { }
+        fn on_connection_removed(&self, _: ConnectionId, _: io::Error) { }
+
+        fn on_handler_panic(&self, id: Option<ConnectionId>, err: Error) {
+            assert!(id.is_none());
+            self.reported.lock().unwrap().push(err.to_string());
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn advance(wheel: &mut TimerWheel<u32>, now: Instant) -> Vec<u32> {
+        let mut expired = Vec::new();
+        wheel.advance(now, &mut expired);
+        expired.sort();
+        expired
+    }
+
+    #[test]
+    fn entries_expire_once_their_deadline_passes() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        wheel.insert(start + ms(25), 1);
+        wheel.insert(start + ms(50), 2);
+
+        // Deadlines are rounded up to the next tick
+        assert!(advance(&mut wheel, start + ms(20)).is_empty());
+        assert_eq!(advance(&mut wheel, start + ms(30)), vec![1]);
+        assert_eq!(advance(&mut wheel, start + ms(50)), vec![2]);
+        assert_eq!(wheel.num_entries, 0);
+    }
+
+    #[test]
+    fn past_deadlines_expire_on_the_next_tick() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        assert!(advance(&mut wheel, start + ms(100)).is_empty());
+
+        wheel.insert(start, 1);
+        assert!(advance(&mut wheel, start + ms(100)).is_empty());
+        assert_eq!(advance(&mut wheel, start + ms(110)), vec![1]);
+    }
+
+    #[test]
+    fn entries_sharing_a_slot_wait_for_their_own_turn() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        let turn = NUM_SLOTS * TICK_MS;
+        wheel.insert(start + ms(50), 1);
+        wheel.insert(start + ms(turn + 50), 2);
+
+        assert_eq!(advance(&mut wheel, start + ms(60)), vec![1]);
+        assert!(advance(&mut wheel, start + ms(turn)).is_empty());
+        assert_eq!(advance(&mut wheel, start + ms(turn + 60)), vec![2]);
+    }
+
+    #[test]
+    fn advancing_past_a_full_turn_expires_everything_due() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        let turn = NUM_SLOTS * TICK_MS;
+        for i in 0..10 {
+            wheel.insert(start + ms(i * 700), i as u32);
+        }
+
+        assert_eq!(advance(&mut wheel, start + ms(3 * turn)), (0..10).collect::<Vec<u32>>());
+        assert_eq!(wheel.next_timeout(start + ms(3 * turn)), None);
+    }
+
+    #[test]
+    fn next_timeout_tracks_the_earliest_entry() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        assert_eq!(wheel.next_timeout(start), None);
+
+        wheel.insert(start + ms(100), 1);
+        wheel.insert(start + ms(40), 2);
+        assert_eq!(wheel.next_timeout(start), Some(ms(40)));
+
+        // Found again by scanning the slots, once the earliest has expired
+        assert_eq!(advance(&mut wheel, start + ms(50)), vec![2]);
+        assert_eq!(wheel.next_timeout(start + ms(50)), Some(ms(50)));
+
+        // Overdue entries are due immediately
+        assert_eq!(wheel.next_timeout(start + ms(150)), Some(ms(0)));
+    }
+
+    #[test]
+    fn next_timeout_looks_past_a_full_turn() {
+        let mut wheel = TimerWheel::<u32>::new();
+        let start = wheel.start;
+        let deadline = NUM_SLOTS * TICK_MS * 2 + 30;
+        wheel.insert(start + ms(deadline), 1);
+        wheel.insert(start + ms(10), 2);
+
+        assert_eq!(advance(&mut wheel, start + ms(10)), vec![2]);
+        assert_eq!(wheel.next_timeout(start + ms(10)), Some(ms(deadline - 10)));
+    }
+
+    #[test]
+    fn cancelled_timer_never_runs() {
+        let scheduler = Scheduler::new(Arc::new(Epoll::new().unwrap()));
+        let handler: EventHandler = Arc::new(Panics::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_clone = runs.clone();
+        let timer = Arc::new(ScheduledTimer::new(None, None, Box::new(move || {
+            runs_clone.fetch_add(1, Ordering::SeqCst);
+        })));
+        let handle = TimerHandle::new(timer.clone());
+
+        assert!(!handle.is_cancelled());
+        handle.cancel();
+        assert!(handle.is_cancelled());
+
+        // The callback, and everything it holds, is released as soon as it is cancelled
+        assert_eq!(Arc::strong_count(&runs), 1);
+        ScheduledTimer::run(timer, &scheduler, &handler);
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn repeating_timer_is_rescheduled_until_cancelled() {
+        let scheduler = Scheduler::new(Arc::new(Epoll::new().unwrap()));
+        let handler: EventHandler = Arc::new(Panics::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_clone = runs.clone();
+        let timer = Arc::new(ScheduledTimer::new(None, Some(ms(10)), Box::new(move || {
+            runs_clone.fetch_add(1, Ordering::SeqCst);
+        })));
+
+        let mut wheel = TimerWheel::<TimerEvent>::new();
+        ScheduledTimer::run(timer.clone(), &scheduler, &handler);
+        scheduler.drain_into(&mut wheel);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(wheel.num_entries, 1);
+
+        TimerHandle::new(timer.clone()).cancel();
+        ScheduledTimer::run(timer, &scheduler, &handler);
+        scheduler.drain_into(&mut wheel);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(wheel.num_entries, 1);
+    }
+
+    #[test]
+    fn panicking_timer_is_reported_and_keeps_repeating() {
+        let scheduler = Scheduler::new(Arc::new(Epoll::new().unwrap()));
+        let panics = Arc::new(Panics::default());
+        let handler: EventHandler = panics.clone();
+        let timer = Arc::new(ScheduledTimer::new(None, Some(ms(10)), Box::new(|| {
+            panic!("timer went off");
+        })));
+
+        let mut wheel = TimerWheel::<TimerEvent>::new();
+        ScheduledTimer::run(timer.clone(), &scheduler, &handler);
+        scheduler.drain_into(&mut wheel);
+        assert_eq!(*panics.reported.lock().unwrap(),
+                   vec!["Handler panicked in scheduled timer: timer went off".to_string()]);
+        assert_eq!(wheel.num_entries, 1);
+        assert!(!timer.is_cancelled());
+    }
+}
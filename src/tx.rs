@@ -0,0 +1,663 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::cmp;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::time::Instant;
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use types::SendStatus;
+use super::Stream;
+
+
+// Most buffers handed to a single send_vectored call while flushing
+const MAX_IOVECS: usize = 64;
+
+/// Outbound queue limits, in bytes, taken from the `Config`.
+#[derive(Clone, Copy)]
+pub struct TxLimits {
+    /// Sends are refused once this many bytes are queued
+    pub high_watermark: usize,
+    /// Sends are accepted again once the queue drains to this many bytes
+    pub low_watermark: usize
+}
+
+/// Region of a file waiting to be sent, through a duplicate of the consumer's fd.
+pub struct FileRange {
+    fd: RawFd,
+    offset: u64,
+    len: usize
+}
+
+impl FileRange {
+    /// Duplicates `fd`, so the consumer is free to close theirs as soon as the send returns.
+    fn new(fd: RawFd, offset: u64, len: usize) -> Result<FileRange, Error> {
+        let dup_fd = unsafe { libc::dup(fd) };
+        if dup_fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(FileRange {
+            fd: dup_fd,
+            offset,
+            len
+        })
+    }
+}
+
+impl Drop for FileRange {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A single send waiting in the queue.
+pub enum Chunk {
+    /// Bytes copied, or moved, into the queue
+    Owned(Vec<u8>),
+    /// Bytes shared with every other connection the same buffer was sent to
+    Shared(Arc<[u8]>),
+    /// Bytes still in a file, sent with `Stream::send_file`
+    File(FileRange)
+}
+
+impl Chunk {
+    /// Returns the chunk's bytes, or None if they are not held in memory.
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Chunk::Owned(ref buf) => Some(&buf[..]),
+            Chunk::Shared(ref buf) => Some(&buf[..]),
+            Chunk::File(_) => None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            Chunk::File(ref range) => range.len == 0,
+            _ => self.mem_len() == 0
+        }
+    }
+
+    /// Returns the number of bytes the chunk holds in memory.
+    fn mem_len(&self) -> usize {
+        match self.as_bytes() {
+            Some(buf) => buf.len(),
+            None => 0
+        }
+    }
+}
+
+/// Outbound state of a connection, guarded by its tx_mutex.
+pub struct TxState {
+    /// Sends the socket has not yet accepted, oldest first
+    queue: VecDeque<Chunk>,
+    /// Bytes of the front chunk already written, when it is held in memory
+    head_offset: usize,
+    /// Total bytes held in memory by the queue. File chunks are not counted.
+    queued_bytes: usize,
+    limits: TxLimits,
+    /// The queue reached the high watermark and has not yet drained to the low watermark
+    pub backpressured: bool,
+    /// Reason the connection will be closed with once the queue has been flushed
+    pub pending_close: Option<Error>,
+    /// When the queue last became non-empty, or last made progress while non-empty
+    stalled_since: Option<Instant>
+}
+
+impl TxState {
+    pub fn new(limits: TxLimits) -> TxState {
+        TxState {
+            queue: VecDeque::new(),
+            head_offset: 0,
+            queued_bytes: 0,
+            limits,
+            backpressured: false,
+            pending_close: None,
+            stalled_since: None
+        }
+    }
+
+    /// Returns true if there is queued data waiting on EPOLLOUT.
+    pub fn is_backlogged(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Returns when the queue began waiting on the socket without making any progress, or None
+    /// if nothing is queued.
+    pub fn stalled_since(&self) -> Option<Instant> {
+        self.stalled_since
+    }
+
+    /// Writes `frame` to the stream, queueing whatever the socket does not accept.
+    pub fn write(&mut self, stream: &mut dyn Stream, frame: Cow<[u8]>)
+        -> Result<SendStatus, Error>
+    {
+        if self.is_backlogged() {
+            self.push(Chunk::Owned(frame.into_owned()));
+            return Ok(SendStatus::Queued);
+        }
+
+        let written = write_all(stream, &frame[..])?;
+        if written == frame.len() {
+            return Ok(SendStatus::Written);
+        }
+
+        let remainder = if written == 0 {
+            frame.into_owned()
+        } else {
+            frame[written..].to_vec()
+        };
+        self.push(Chunk::Owned(remainder));
+
+        Ok(SendStatus::Queued)
+    }
+
+    /// Writes `bufs`, in order, to the stream, queueing whatever the socket does not accept.
+    pub fn write_vectored(&mut self, stream: &mut dyn Stream, bufs: &[IoSlice])
+        -> Result<SendStatus, Error>
+    {
+        let total = bufs.iter().fold(0usize, |total, buf| total + buf.len());
+
+        let mut written = 0usize;
+        if !self.is_backlogged() {
+            while written < total {
+                let slices = remaining_slices(bufs, written);
+                match stream.send_vectored(&slices[..]) {
+                    Ok(0) => return Err(write_zero()),
+                    Ok(num_written) => written += num_written,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err)
+                }
+            }
+
+            if written == total {
+                return Ok(SendStatus::Written);
+            }
+        }
+
+        let mut remainder = Vec::<u8>::with_capacity(total - written);
+        for slice in remaining_slices(bufs, written).iter() {
+            remainder.extend_from_slice(slice);
+        }
+        self.push(Chunk::Owned(remainder));
+
+        Ok(SendStatus::Queued)
+    }
+
+    /// Writes `buf` to the stream, queueing a reference to whatever the socket does not accept.
+    pub fn write_shared(&mut self, stream: &mut dyn Stream, buf: Arc<[u8]>)
+        -> Result<SendStatus, Error>
+    {
+        if self.is_backlogged() {
+            self.push(Chunk::Shared(buf));
+            return Ok(SendStatus::Queued);
+        }
+
+        let written = write_all(stream, &buf[..])?;
+        if written == buf.len() {
+            return Ok(SendStatus::Written);
+        }
+
+        // The queue was empty, so this becomes the front chunk
+        self.push(Chunk::Shared(buf));
+        self.head_offset = written;
+        self.queued_bytes -= written;
+        self.backpressured = self.queued_bytes >= self.limits.high_watermark;
+
+        Ok(SendStatus::Queued)
+    }
+
+    /// Writes `len` bytes of the file `fd`, starting at `offset`, to the stream, queueing
+    /// whatever the socket does not accept.
+    pub fn write_file(&mut self, stream: &mut dyn Stream, fd: RawFd, offset: u64, len: usize)
+        -> Result<SendStatus, Error>
+    {
+        let mut written = 0usize;
+        if !self.is_backlogged() {
+            while written < len {
+                match stream.send_file(fd, offset + written as u64, len - written) {
+                    Ok(0) => return Err(write_zero()),
+                    Ok(num_written) => written += num_written,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err)
+                }
+            }
+
+            if written == len {
+                return Ok(SendStatus::Written);
+            }
+        }
+
+        let range = FileRange::new(fd, offset + written as u64, len - written)?;
+        self.push(Chunk::File(range));
+
+        Ok(SendStatus::Queued)
+    }
+
+    /// Adds `chunk` to the back of the queue.
+    fn push(&mut self, chunk: Chunk) {
+        // Nothing would ever be written for it, and flushing would mistake that for a failure
+        if chunk.is_empty() {
+            return;
+        }
+
+        if self.queue.is_empty() {
+            self.stalled_since = Some(Instant::now());
+        }
+
+        self.queued_bytes += chunk.mem_len();
+        self.queue.push_back(chunk);
+
+        if self.queued_bytes >= self.limits.high_watermark {
+            self.backpressured = true;
+        }
+    }
+
+    /// Writes queued data to the stream until the queue is empty or the socket stops accepting.
+    ///
+    /// Consecutive in-memory chunks are handed to the stream together through `send_vectored`.
+    /// Returns `ErrorKind::WouldBlock` if data remains queued.
+    pub fn flush(&mut self, stream: &mut dyn Stream) -> Result<(), Error> {
+        let mut progressed = false;
+        let result = self.flush_queue(stream, &mut progressed);
+        self.stalled_since = match result {
+            Ok(()) => None,
+            Err(_) if progressed => Some(Instant::now()),
+            Err(_) => self.stalled_since
+        };
+
+        result
+    }
+
+    fn flush_queue(&mut self, stream: &mut dyn Stream, progressed: &mut bool)
+        -> Result<(), Error>
+    {
+        loop {
+            let result = match self.queue.front_mut() {
+                Some(&mut Chunk::File(ref mut range)) => {
+                    match stream.send_file(range.fd, range.offset, range.len) {
+                        Ok(0) => Err(write_zero()),
+                        Ok(num_written) => {
+                            *progressed = true;
+                            range.offset += num_written as u64;
+                            range.len -= num_written;
+                            Ok(range.len == 0)
+                        }
+                        Err(err) => Err(err)
+                    }
+                }
+                Some(_) => {
+                    let result = {
+                        let mut slices = Vec::<IoSlice>::with_capacity(MAX_IOVECS);
+                        for chunk in self.queue.iter().take(MAX_IOVECS) {
+                            match chunk.as_bytes() {
+                                Some(buf) if slices.is_empty() => {
+                                    slices.push(IoSlice::new(&buf[self.head_offset..]))
+                                }
+                                Some(buf) => slices.push(IoSlice::new(buf)),
+                                None => break
+                            }
+                        }
+
+                        if slices.len() == 1 {
+                            stream.send(&slices[0])
+                        } else {
+                            stream.send_vectored(&slices[..])
+                        }
+                    };
+
+                    match result {
+                        Ok(0) => Err(write_zero()),
+                        Ok(num_written) => {
+                            *progressed = true;
+                            self.consume(num_written);
+                            Ok(false)
+                        }
+                        Err(err) => Err(err)
+                    }
+                }
+                None => return Ok(())
+            };
+
+            // A finished file chunk is dropped here, closing its fd
+            if result? {
+                self.queue.pop_front();
+            }
+        }
+    }
+
+    /// Drops `num_written` bytes from the front of the in-memory chunks.
+    fn consume(&mut self, mut num_written: usize) {
+        self.queued_bytes -= num_written;
+        while num_written > 0 {
+            let remaining = self.queue[0].mem_len() - self.head_offset;
+            if num_written < remaining {
+                self.head_offset += num_written;
+                return;
+            }
+
+            num_written -= remaining;
+            self.head_offset = 0;
+            self.queue.pop_front();
+        }
+    }
+
+    /// Empties the queue, returning everything in it as one buffer, with queued file contents
+    /// read into memory.
+    pub fn take_queued(&mut self) -> Result<Vec<u8>, Error> {
+        let mut queued = Vec::<u8>::with_capacity(self.queued_bytes);
+        for (i, chunk) in self.queue.iter().enumerate() {
+            // head_offset only ever applies to an in-memory front chunk
+            let skip = if i == 0 { self.head_offset } else { 0 };
+            match *chunk {
+                Chunk::Owned(ref buf) => queued.extend_from_slice(&buf[skip..]),
+                Chunk::Shared(ref buf) => queued.extend_from_slice(&buf[skip..]),
+                Chunk::File(ref range) => read_file_range(range, &mut queued)?
+            }
+        }
+
+        self.queue.clear();
+        self.head_offset = 0;
+        self.queued_bytes = 0;
+        self.backpressured = false;
+        self.stalled_since = None;
+
+        Ok(queued)
+    }
+
+    /// Queues `buf` behind anything already queued, without trying to write it first.
+    pub fn enqueue(&mut self, buf: Vec<u8>) {
+        self.push(Chunk::Owned(buf));
+    }
+
+    /// Clears the backpressured state once the queue has drained to the low watermark.
+    ///
+    /// Returns true if the state was cleared by this call.
+    pub fn relieve_backpressure(&mut self) -> bool {
+        if self.backpressured && self.queued_bytes <= self.limits.low_watermark {
+            self.backpressured = false;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Writes `buf` to the stream until it is all written or the socket stops accepting, and
+/// returns the number of bytes written.
+fn write_all(stream: &mut dyn Stream, buf: &[u8]) -> Result<usize, Error> {
+    let mut written = 0usize;
+    while written < buf.len() {
+        match stream.send(&buf[written..]) {
+            Ok(0) => return Err(write_zero()),
+            Ok(num_written) => written += num_written,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err)
+        }
+    }
+
+    Ok(written)
+}
+
+/// Returns what is left of `bufs` once the first `skip` bytes have been written.
+fn remaining_slices<'a>(bufs: &'a [IoSlice], mut skip: usize) -> Vec<IoSlice<'a>> {
+    let mut slices = Vec::<IoSlice>::with_capacity(cmp::min(bufs.len(), MAX_IOVECS));
+    for buf in bufs.iter() {
+        let buf: &'a [u8] = buf;
+        if skip >= buf.len() {
+            skip -= buf.len();
+            continue;
+        }
+        slices.push(IoSlice::new(&buf[skip..]));
+        skip = 0;
+    }
+
+    slices
+}
+
+/// Appends the contents of `range` to `buf`.
+fn read_file_range(range: &FileRange, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let start = buf.len();
+    buf.resize(start + range.len, 0);
+
+    let mut read = 0usize;
+    while read < range.len {
+        let result = unsafe {
+            libc::pread(range.fd,
+                        buf[start + read..].as_mut_ptr() as *mut libc::c_void,
+                        range.len - read,
+                        (range.offset + read as u64) as libc::off_t)
+        };
+        match result {
+            0 => return Err(Error::new(ErrorKind::UnexpectedEof, "Queued file was truncated")),
+            n if n > 0 => read += n as usize,
+            _ => {
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_zero() -> Error {
+    Error::new(ErrorKind::WriteZero, "Stream accepted no bytes")
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::io::{RawFd, AsRawFd};
+
+    use types::SendStatus;
+    use super::super::Stream;
+    use super::*;
+
+    /// Stream that accepts up to `budget` more bytes, then reports the socket full.
+    struct MockStream {
+        budget: usize,
+        sent: Vec<u8>
+    }
+
+    impl MockStream {
+        fn new(budget: usize) -> MockStream {
+            MockStream {
+                budget,
+                sent: Vec::new()
+            }
+        }
+    }
+
+    impl Stream for MockStream {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn send(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if self.budget == 0 {
+                return Err(Error::new(ErrorKind::WouldBlock, "Socket full"));
+            }
+
+            let num_written = cmp::min(self.budget, buf.len());
+            self.budget -= num_written;
+            self.sent.extend_from_slice(&buf[..num_written]);
+            Ok(num_written)
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for MockStream {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    fn tx_state(high_watermark: usize, low_watermark: usize) -> TxState {
+        TxState::new(TxLimits {
+            high_watermark,
+            low_watermark
+        })
+    }
+
+    fn flush_error(tx: &mut TxState, stream: &mut MockStream) -> Option<ErrorKind> {
+        tx.flush(stream).err().map(|err| err.kind())
+    }
+
+    #[test]
+    fn whole_write_is_not_queued() {
+        let mut tx = tx_state(100, 10);
+        let mut stream = MockStream::new(64);
+
+        let status = tx.write(&mut stream, Cow::Borrowed(b"hello")).unwrap();
+        assert_eq!(status, SendStatus::Written);
+        assert!(!tx.is_backlogged());
+        assert_eq!(tx.stalled_since(), None);
+        assert_eq!(stream.sent, b"hello");
+    }
+
+    #[test]
+    fn partial_write_queues_remainder() {
+        let mut tx = tx_state(100, 10);
+        let mut stream = MockStream::new(3);
+
+        let status = tx.write(&mut stream, Cow::Borrowed(b"abcdef")).unwrap();
+        assert_eq!(status, SendStatus::Queued);
+        assert!(tx.is_backlogged());
+        assert!(tx.stalled_since().is_some());
+        assert_eq!(tx.queued_bytes, 3);
+        assert_eq!(stream.sent, b"abc");
+    }
+
+    #[test]
+    fn sends_behind_a_backlog_are_queued_in_order() {
+        let mut tx = tx_state(100, 10);
+        let mut stream = MockStream::new(0);
+        tx.write(&mut stream, Cow::Borrowed(b"one")).unwrap();
+
+        // The socket has room again, but "two" must not overtake "one"
+        stream.budget = 64;
+        let status = tx.write(&mut stream, Cow::Borrowed(b"two")).unwrap();
+        assert_eq!(status, SendStatus::Queued);
+        assert!(stream.sent.is_empty());
+
+        tx.flush(&mut stream).unwrap();
+        assert_eq!(stream.sent, b"onetwo");
+        assert!(!tx.is_backlogged());
+    }
+
+    #[test]
+    fn partial_flush_advances_head_offset() {
+        let mut tx = tx_state(100, 10);
+        let mut stream = MockStream::new(0);
+        tx.write(&mut stream, Cow::Borrowed(b"abcdef")).unwrap();
+
+        stream.budget = 4;
+        assert_eq!(flush_error(&mut tx, &mut stream), Some(ErrorKind::WouldBlock));
+        assert_eq!(tx.head_offset, 4);
+        assert_eq!(tx.queued_bytes, 2);
+        assert!(tx.stalled_since().is_some());
+
+        stream.budget = 64;
+        tx.flush(&mut stream).unwrap();
+        assert_eq!(stream.sent, b"abcdef");
+        assert_eq!(tx.head_offset, 0);
+        assert_eq!(tx.queued_bytes, 0);
+        assert_eq!(tx.stalled_since(), None);
+    }
+
+    #[test]
+    fn partial_flush_spans_chunks() {
+        let mut tx = tx_state(100, 10);
+        let mut stream = MockStream::new(5);
+        tx.enqueue(b"abc".to_vec());
+        tx.enqueue(b"def".to_vec());
+        tx.enqueue(b"ghi".to_vec());
+
+        // "abc" is written and dropped from the queue, then 2 bytes into "def"
+        assert_eq!(flush_error(&mut tx, &mut stream), Some(ErrorKind::WouldBlock));
+        assert_eq!(tx.queue.len(), 2);
+        assert_eq!(tx.head_offset, 2);
+        assert_eq!(tx.queued_bytes, 4);
+
+        stream.budget = 64;
+        tx.flush(&mut stream).unwrap();
+        assert_eq!(stream.sent, b"abcdefghi");
+    }
+
+    #[test]
+    fn watermarks_set_and_relieve_backpressure() {
+        let mut tx = tx_state(8, 2);
+        let mut stream = MockStream::new(0);
+
+        tx.write(&mut stream, Cow::Borrowed(b"1234")).unwrap();
+        assert!(!tx.backpressured);
+        tx.write(&mut stream, Cow::Borrowed(b"5678")).unwrap();
+        assert!(tx.backpressured);
+
+        // Drained, but still above the low watermark
+        stream.budget = 5;
+        assert_eq!(flush_error(&mut tx, &mut stream), Some(ErrorKind::WouldBlock));
+        assert_eq!(tx.queued_bytes, 3);
+        assert!(!tx.relieve_backpressure());
+        assert!(tx.backpressured);
+
+        stream.budget = 1;
+        assert_eq!(flush_error(&mut tx, &mut stream), Some(ErrorKind::WouldBlock));
+        assert_eq!(tx.queued_bytes, 2);
+        assert!(tx.relieve_backpressure());
+        assert!(!tx.backpressured);
+
+        // Only reported once per high watermark crossing
+        assert!(!tx.relieve_backpressure());
+    }
+
+    #[test]
+    fn partial_shared_write_keeps_head_offset() {
+        let mut tx = tx_state(4, 0);
+        let mut stream = MockStream::new(2);
+        let buf: Arc<[u8]> = Arc::from(&b"abcdef"[..]);
+
+        let status = tx.write_shared(&mut stream, buf).unwrap();
+        assert_eq!(status, SendStatus::Queued);
+        assert_eq!(tx.head_offset, 2);
+        assert_eq!(tx.queued_bytes, 4);
+        assert!(tx.backpressured);
+
+        tx.enqueue(b"gh".to_vec());
+        assert_eq!(tx.take_queued().unwrap(), b"cdefgh");
+        assert!(!tx.is_backlogged());
+        assert!(!tx.backpressured);
+        assert_eq!(tx.head_offset, 0);
+        assert_eq!(tx.queued_bytes, 0);
+    }
+
+    #[test]
+    fn empty_chunks_are_not_queued() {
+        let mut tx = tx_state(100, 10);
+        tx.enqueue(Vec::new());
+        assert!(!tx.is_backlogged());
+        assert_eq!(tx.stalled_since(), None);
+    }
+}
@@ -6,138 +6,612 @@
 // http://mozilla.org/MPL/2.0/.
 
 
-use std::io::{Error, ErrorKind};
-use std::cell::UnsafeCell;
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::net::SocketAddr;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::time::{Duration, Instant};
+use std::thread::JoinHandle;
+use std::os::unix::net::UnixStream;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::os::unix::io::{RawFd, AsRawFd};
 
 use libc;
-use simple_slab::Slab;
 
+use slab::{Slab, Token, Iter as SlabIter};
+use listener::Listener;
+use epoll::{Epoll, DEFAULT_EVENTS};
+use tx::TxState;
+use timer::{Scheduler, ScheduledTimer, TimerHandle};
+use groups::Groups;
+use server;
+use server::Acceptor;
+use error::Error as HydrogenError;
 use super::{Stream, Handler};
 
 
 /// Memory region for all concurrent connections.
-pub type ConnectionSlab = Arc<MutSlab>;
+pub type ConnectionSlab = Arc<RwLock<Connections>>;
 /// Protected memory region for newly accepted connections.
-pub type NewConnectionSlab = Arc<Mutex<Slab<Connection>>>;
-/// Queue of Connections needing various I/O operations.
-pub type IoQueue = Arc<Mutex<Vec<IoPair>>>;
+pub type NewConnectionSlab = Arc<Mutex<Vec<Connection>>>;
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum IoEvent {
     /// Epoll reported data is available on the socket for reading
-    ReadAvailable,
+    Read,
     /// Epoll reported the socket is writable
-    WriteAvailable,
+    Write,
     /// Epoll reported the socket is both writable and has data available for reading
-    ReadWriteAvailable
+    ReadWrite
 }
 
-#[derive(Clone)]
-pub struct IoPair {
-    /// The type of I/O needed on this Connection
-    pub event: IoEvent,
-    /// The connection `event` is paired with
-    pub arc_connection: Arc<Connection>
+/// Identifier assigned to every accepted connection.
+///
+/// File descriptors are reused by the kernel as soon as they are closed, ids are never reused
+/// for the lifetime of the process. They should be preferred over fds as keys for any
+/// per-connection state kept by the consumer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Identifier of one of the server's listeners, as set in its `ListenerConfig`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ListenerId(pub u32);
+
+impl fmt::Display for ListenerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Credentials of the process on the other end of a Unix socket, read with `SO_PEERCRED` when
+/// the connection was accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t
 }
 
 pub struct Connection {
+    /// Identifier reported to the handler for this connection.
+    pub id: ConnectionId,
     /// Underlying file descriptor.
     pub fd: RawFd,
+    /// Peer credentials, for connections accepted on a Unix listener.
+    pub peer_credentials: Option<PeerCredentials>,
+    /// Listener the connection was accepted on, or None if it was made through
+    /// `ServerHandle::connect`.
+    pub listener: Option<ListenerId>,
+    /// Key into the ConnectionSlab, also used as the epoll user data for this fd.
+    pub token: Token,
+    /// Epoll instance of the reactor the connection belongs to.
+    pub epoll: Arc<Epoll>,
     /// A Some(Error) options means this connection is in
     /// an error'd state and should be closed.
     pub err_mutex: Mutex<Option<Error>>,
-    /// Mutex to ensure thread safe, ordered writes to our streams.
-    /// They may have internal buffers
-    pub tx_mutex: Mutex<()>,
-    /// Socket (Stream implemented trait-object).
-    pub stream: Arc<UnsafeCell<Stream>>
+    /// Mutex to ensure thread safe, ordered writes to our streams, and guarding the
+    /// connection's outbound queue.
+    pub tx_mutex: Mutex<TxState>,
+    /// Socket (Stream implemented trait-object). Locked after tx_mutex when both are needed.
+    pub stream: Mutex<Box<dyn Stream>>,
+    /// The handler's per-connection state, as returned from on_new_connection
+    pub context: Box<dyn Any + Send + Sync>,
+    /// Handler to report backpressure to, from whichever thread is sending
+    pub handler: EventHandler,
+    /// When the connection was accepted
+    pub accepted_at: Instant,
+    /// Milliseconds after accepted_at of the last read or write
+    pub last_activity: AtomicU64,
+    /// Set once epoll has reported the connection readable
+    pub first_byte_received: AtomicBool,
+    /// Set until the connect of a connection made through `ServerHandle::connect` has finished
+    pub connecting: AtomicBool,
+    /// Timeouts the event loop enforces on this connection
+    pub timeouts: Timeouts,
+    /// Hands timers scheduled on this connection to the event loop
+    pub scheduler: Scheduler
+}
+
+impl Connection {
+    /// Puts the connection into an error'd state with `reason`, unless it already is in one, and
+    /// wakes its event loop to remove it.
+    pub fn close_with(&self, reason: Error) {
+        { // Mutex lock
+            let mut err_state = match self.err_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            if err_state.is_none() {
+                *err_state = Some(reason);
+            }
+        } // Mutex unlock
+
+        self.epoll.wake();
+    }
+
+    /// Re-arms the connection in its epoll instance with the event mask, plus `EPOLLOUT` if
+    /// anything is queued.
+    pub fn rearm(&self, flags: i32) {
+        let fd = self.fd;
+        let mut events = DEFAULT_EVENTS | flags;
+
+        // An error'd connection may have been closed already, and its fd handed out again
+        let tx_state = match self.tx_mutex.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        if self.is_errored() {
+            trace!("Skipping re-arm of error'd fd: {}", fd);
+            return;
+        }
+
+        // The mask is replaced, not added to, so a backlog from a send made during a read event
+        // must not be lost when that read event re-arms
+        if tx_state.is_backlogged() {
+            events |= libc::EPOLLOUT;
+        }
+
+        trace!("EPOLL_CTL_MOD   fd: {}    flags: {:#b}", fd, (flags as u32));
+
+        if let Err(err) = self.epoll.modify(fd, events, self.token) {
+            error!("EPOLL_CTL_MOD   fd: {}    {}", fd, err);
+
+            { // Mutex lock
+                let mut err_state = match self.err_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                *err_state = Some(err);
+            } // Mutex unlock
+
+            self.epoll.wake();
+        }
+    }
+
+    /// Records that the connection has just been read from or written to.
+    pub fn touch(&self) {
+        let elapsed = self.accepted_at.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        self.last_activity.store(elapsed_ms, Ordering::Relaxed);
+    }
+
+    /// Returns when the connection was last read from or written to.
+    pub fn last_activity(&self) -> Instant {
+        self.accepted_at + Duration::from_millis(self.last_activity.load(Ordering::Relaxed))
+    }
+
+    /// Returns true if the connection is in an error'd state and will be, or has been, removed.
+    pub fn is_errored(&self) -> bool {
+        let err_state = match self.err_mutex.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        err_state.is_some()
+    }
+}
+
+/// All connections in the epoll interest list, indexed by both slab token and ConnectionId.
+///
+/// Only the event loop inserts and removes, lookups may come from any thread.
+pub struct Connections {
+    slab: Slab<Arc<Connection>>,
+    ids: HashMap<ConnectionId, Token>
+}
+
+impl Connections {
+    pub fn new(capacity: usize) -> Connections {
+        Connections {
+            slab: Slab::new(capacity),
+            ids: HashMap::with_capacity(capacity)
+        }
+    }
+
+    /// Stores the connection, assigning its token.
+    pub fn insert(&mut self, mut connection: Connection) -> Arc<Connection> {
+        let id = connection.id;
+        let token = self.slab.insert_with(move |token| {
+            connection.token = token;
+            Arc::new(connection)
+        });
+        self.ids.insert(id, token);
+
+        self.slab.get(token).unwrap().clone()
+    }
+
+    pub fn remove(&mut self, token: Token) -> Option<Arc<Connection>> {
+        let removed = self.slab.remove(token);
+        if let Some(ref arc_connection) = removed {
+            self.ids.remove(&arc_connection.id);
+        }
+
+        removed
+    }
+
+    pub fn get(&self, token: Token) -> Option<&Arc<Connection>> {
+        self.slab.get(token)
+    }
+
+    pub fn find(&self, id: ConnectionId) -> Option<&Arc<Connection>> {
+        match self.ids.get(&id) {
+            Some(token) => self.slab.get(*token),
+            None => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn iter(&self) -> SlabIter<'_, Arc<Connection>> {
+        self.slab.iter()
+    }
+}
+
+/// The connection slab of every reactor, for lookups that span the whole server.
+///
+/// Each connection is in the slab of the reactor whose epoll instance it is registered with.
+#[derive(Clone)]
+pub struct ConnectionShards {
+    slabs: Arc<Vec<ConnectionSlab>>
+}
+
+impl ConnectionShards {
+    pub fn new(slabs: Vec<ConnectionSlab>) -> ConnectionShards {
+        ConnectionShards {
+            slabs: Arc::new(slabs)
+        }
+    }
+
+    /// Returns the connection with `id`, from whichever shard it is in.
+    pub fn find(&self, id: ConnectionId) -> Option<Arc<Connection>> {
+        for slab in self.slabs.iter() {
+            let connections = match slab.read() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            if let Some(arc_connection) = connections.find(id) {
+                return Some(arc_connection.clone());
+            }
+        }
+
+        None
+    }
+
+    /// Returns every connection, reading one shard at a time.
+    pub fn all(&self) -> Vec<Arc<Connection>> {
+        let mut all = Vec::<Arc<Connection>>::new();
+        for slab in self.slabs.iter() {
+            let connections = match slab.read() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            all.reserve(connections.len());
+            for (_, arc_connection) in connections.iter() {
+                all.push(arc_connection.clone());
+            }
+        }
+
+        all
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, ConnectionSlab> {
+        self.slabs.iter()
+    }
+}
+
+/// Per-connection timeouts, taken from the `Config`.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub idle: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub write_stall: Option<Duration>
 }
-unsafe impl Send for Connection {}
-unsafe impl Sync for Connection {}
 
+impl Timeouts {
+    /// Returns true if any timeout is enabled.
+    pub fn any(&self) -> bool {
+        self.idle.is_some() || self.first_byte.is_some() || self.write_stall.is_some()
+    }
+}
 
-pub struct MutSlab {
-    pub inner: UnsafeCell<Slab<Arc<Connection>>>
+/// Object safe view of a `Handler`, with its `Context` type erased, as stored by the server.
+pub trait ErasedHandler : Send + Sync {
+    fn on_server_created(&self, listener: ListenerId, fd: RawFd);
+    fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+        -> (Box<dyn Stream>, Box<dyn Any + Send + Sync>);
+    fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>);
+    fn on_connection_removed(&self, id: ConnectionId, err: Error);
+    fn on_send_error(&self, id: ConnectionId, err: Error);
+    fn on_backpressure(&self, id: ConnectionId);
+    fn on_writable(&self, socket: HydrogenSocket);
+    fn on_server_error(&self, err: HydrogenError);
+    fn on_handler_panic(&self, id: Option<ConnectionId>, err: HydrogenError);
+    fn on_connect_result(&self, id: ConnectionId, result: Result<HydrogenSocket, Error>);
+    fn on_connection_handoff(&self, socket: HydrogenSocket) -> Option<Vec<u8>>;
+    fn on_connection_adopted(&self,
+                             id: ConnectionId,
+                             listener: ListenerId,
+                             fd: RawFd,
+                             blob: Vec<u8>)
+                             -> Option<(Box<dyn Stream>, Box<dyn Any + Send + Sync>)>;
 }
-unsafe impl Send for MutSlab {}
-unsafe impl Sync for MutSlab {}
 
-pub struct EventHandler(pub *mut Handler);
-unsafe impl Send for EventHandler {}
-unsafe impl Sync for EventHandler {}
-impl Clone for EventHandler {
-    fn clone(&self) -> EventHandler {
-        let EventHandler(ptr) = *self;
-        unsafe {
-            let same_location = &mut *ptr;
-            EventHandler(same_location)
+impl<T: Handler> ErasedHandler for T {
+    fn on_server_created(&self, listener: ListenerId, fd: RawFd) {
+        Handler::on_server_created(self, listener, fd)
+    }
+
+    fn on_new_connection(&self, id: ConnectionId, listener: ListenerId, fd: RawFd)
+        -> (Box<dyn Stream>, Box<dyn Any + Send + Sync>)
+    {
+        let (stream, context) = Handler::on_new_connection(self, id, listener, fd);
+        (stream, Box::new(context))
+    }
+
+    fn on_data_received(&self, socket: HydrogenSocket, buf: Vec<u8>) {
+        Handler::on_data_received(self, socket.retype(), buf)
+    }
+
+    fn on_connection_removed(&self, id: ConnectionId, err: Error) {
+        Handler::on_connection_removed(self, id, err)
+    }
+
+    fn on_send_error(&self, id: ConnectionId, err: Error) {
+        Handler::on_send_error(self, id, err)
+    }
+
+    fn on_backpressure(&self, id: ConnectionId) {
+        Handler::on_backpressure(self, id)
+    }
+
+    fn on_writable(&self, socket: HydrogenSocket) {
+        Handler::on_writable(self, socket.retype())
+    }
+
+    fn on_server_error(&self, err: HydrogenError) {
+        Handler::on_server_error(self, err)
+    }
+
+    fn on_handler_panic(&self, id: Option<ConnectionId>, err: HydrogenError) {
+        Handler::on_handler_panic(self, id, err)
+    }
+
+    fn on_connect_result(&self, id: ConnectionId, result: Result<HydrogenSocket, Error>) {
+        Handler::on_connect_result(self, id, result.map(HydrogenSocket::retype))
+    }
+
+    fn on_connection_handoff(&self, socket: HydrogenSocket) -> Option<Vec<u8>> {
+        Handler::on_connection_handoff(self, socket.retype())
+    }
+
+    fn on_connection_adopted(&self,
+                             id: ConnectionId,
+                             listener: ListenerId,
+                             fd: RawFd,
+                             blob: Vec<u8>)
+                             -> Option<(Box<dyn Stream>, Box<dyn Any + Send + Sync>)>
+    {
+        match Handler::on_connection_adopted(self, id, listener, fd, blob) {
+            Some((stream, context)) => Some((stream, Box::new(context))),
+            None => None
         }
     }
 }
 
+/// The consumer's handler, shared by every thread of the server.
+pub type EventHandler = Arc<dyn ErasedHandler>;
+
+/// Outcome of a successful `HydrogenSocket::send`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    /// The stream accepted the entire buffer.
+    Written,
+    /// The socket did not accept all of it, and the rest is held in the connection's outbound
+    /// queue, to be flushed once epoll reports the socket writable again. Once
+    /// `tx_high_watermark` bytes are queued, `on_backpressure` is called and further sends are
+    /// refused with `Error::Backpressure`, until the queue drains to `tx_low_watermark` and
+    /// `on_writable` is called. Producers should slow down.
+    Queued
+}
+
 /// Thread-safe wrapper for consumer interaction with streams.
-pub struct HydrogenSocket {
-    /// The connection this socket represents
+///
+/// `C` is the `Handler::Context` type of the server the connection belongs to.
+pub struct HydrogenSocket<C = ()> {
+    /// The connection this socket represents, along with the epoll instance it is in
     arc_connection: Arc<Connection>,
-    /// Function responsible for re-arming fd in epoll instance
-    rearm_fn: unsafe fn(&Arc<Connection>, i32)
+    /// The context itself lives in the connection, type erased
+    context_type: PhantomData<fn() -> C>
 }
 
-impl Clone for HydrogenSocket {
-    fn clone(&self) -> HydrogenSocket {
-        let fn_ptr = self.rearm_fn;
+impl<C> Clone for HydrogenSocket<C> {
+    fn clone(&self) -> HydrogenSocket<C> {
         HydrogenSocket {
             arc_connection: self.arc_connection.clone(),
-            rearm_fn: fn_ptr
+            context_type: PhantomData
         }
     }
 }
 
-impl HydrogenSocket {
-    pub fn new(arc_connection: Arc<Connection>,
-               rearm_fn: unsafe fn(&Arc<Connection>, i32))
-               -> HydrogenSocket
-    {
+impl<C: 'static> HydrogenSocket<C> {
+    pub fn new(arc_connection: Arc<Connection>) -> HydrogenSocket<C> {
         HydrogenSocket {
-            arc_connection: arc_connection,
-            rearm_fn: rearm_fn
+            arc_connection,
+            context_type: PhantomData
         }
     }
 
-    pub fn send(&self, buf: &[u8]) {
-        let err;
+    /// Returns the same socket, typed for a server whose handler uses `D` as its context.
+    fn retype<D>(self) -> HydrogenSocket<D> {
+        HydrogenSocket {
+            arc_connection: self.arc_connection,
+            context_type: PhantomData
+        }
+    }
+
+    /// Returns the state created for this connection by `Handler::on_new_connection`.
+    ///
+    /// Epoll reports a connection readable again only once the previous read has been handled,
+    /// so `on_data_received` calls for a connection never overlap, and state only used from
+    /// there is never contended. Other callbacks, timers, and sockets obtained through the
+    /// `ServerHandle` may use the context concurrently with them.
+    pub fn context(&self) -> &C {
+        match self.arc_connection.context.downcast_ref::<C>() {
+            Some(context) => context,
+            None => panic!("Connection context is not a {}", ::std::any::type_name::<C>())
+        }
+    }
+
+    /// Sends `buf` through the connection's stream.
+    ///
+    /// `buf` is passed through `Stream::encode`, then written as far as the socket allows. The
+    /// rest is kept in the connection's outbound queue, returning `Ok(SendStatus::Queued)`, and
+    /// flushed once epoll reports the socket writable. Any failure discovered while flushing is
+    /// reported through `Handler::on_send_error`.
+    ///
+    /// Once `Config::tx_high_watermark` bytes are queued, `Handler::on_backpressure` is called
+    /// and sends fail with `Err(Error::Backpressure)` until the queue drains to
+    /// `Config::tx_low_watermark` and `Handler::on_writable` is called.
+    /// `Err(Error::ConnectionClosed)` means the connection is being removed, and nothing more
+    /// should be produced for it. If the stream panics, the connection is removed, and
+    /// `Err(Error::HandlerPanic)` is returned.
+    pub fn send(&self, buf: &[u8]) -> Result<SendStatus, HydrogenError> {
+        self.send_with("Stream::send", |tx_state, stream| {
+            let frame = stream.encode(buf);
+            tx_state.write(stream, frame)
+        })
+    }
+
+    /// Sends every buffer in `bufs`, in order, through `Stream::send_vectored`.
+    ///
+    /// The buffers are written exactly as passed, they are not passed through `Stream::encode`.
+    /// Only what the socket does not accept right away is copied into the outbound queue.
+    /// Otherwise behaves like `send`.
+    pub fn send_vectored(&self, bufs: &[IoSlice]) -> Result<SendStatus, HydrogenError> {
+        self.send_with("Stream::send_vectored", |tx_state, stream| {
+            tx_state.write_vectored(stream, bufs)
+        })
+    }
+
+    /// Sends `buf` without copying it, so the same buffer can be sent to any number of
+    /// connections. Whatever the socket does not accept right away is queued by reference.
+    ///
+    /// The buffer is written exactly as passed, it is not passed through `Stream::encode`.
+    /// Otherwise behaves like `send`.
+    pub fn send_shared(&self, buf: Arc<[u8]>) -> Result<SendStatus, HydrogenError> {
+        self.send_with("Stream::send", move |tx_state, stream| tx_state.write_shared(stream, buf))
+    }
+
+    /// Sends `len` bytes of the file `fd`, starting at `offset`, through `Stream::send_file`.
+    ///
+    /// The file's contents are written exactly as stored, they are not passed through
+    /// `Stream::encode`. If any of it has to be queued, `fd` is duplicated first, so the caller
+    /// may close `fd` as soon as this returns. Queued file contents are read as they are sent,
+    /// and do not count towards `Config::tx_high_watermark`. Otherwise behaves like `send`.
+    pub fn send_file(&self, fd: RawFd, offset: u64, len: usize)
+        -> Result<SendStatus, HydrogenError>
+    {
+        self.send_with("Stream::send_file", |tx_state, stream| {
+            tx_state.write_file(stream, fd, offset, len)
+        })
+    }
+
+    /// Runs `write` with the connection's outbound queue and stream while holding tx_mutex,
+    /// then re-arms for EPOLLOUT, reports backpressure, or puts the connection into an error'd
+    /// state, depending on the outcome. A panic in the stream is reported as one in `method`.
+    fn send_with<F>(&self, method: &'static str, write: F) -> Result<SendStatus, HydrogenError>
+        where F: FnOnce(&mut TxState, &mut dyn Stream) -> Result<SendStatus, Error>
+    {
+        let was_backlogged;
+        let now_backpressured;
+        let result;
         { // Mutex lock
-            let _ = match self.arc_connection.tx_mutex.lock() {
+            let mut tx_state = match self.arc_connection.tx_mutex.lock() {
                 Ok(g) => g,
                 Err(p) => p.into_inner()
             };
 
-            let stream_ptr = self.arc_connection.stream.get();
-            let write_result = unsafe {
-                (*stream_ptr).send(buf)
-            };
-            if write_result.is_ok() {
-                trace!("HydrogenSocket.send OK");
-                return;
+            // The fd is closed, while holding tx_mutex, once a connection in an error'd state
+            // is removed. It may already belong to something else.
+            if self.arc_connection.is_errored() || tx_state.pending_close.is_some() {
+                trace!("HydrogenSocket.send on closed connection");
+                return Err(HydrogenError::ConnectionClosed);
             }
+            if tx_state.backpressured {
+                trace!("HydrogenSocket.send on backpressured connection");
+                return Err(HydrogenError::Backpressure);
+            }
+
+            was_backlogged = tx_state.is_backlogged();
+            result = { // Mutex lock
+                let mut stream = match self.arc_connection.stream.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
 
-            err = write_result.unwrap_err();
+                panic::catch_unwind(AssertUnwindSafe(|| write(&mut tx_state, &mut **stream)))
+            }; // Mutex unlock
+            now_backpressured = tx_state.backpressured;
         } // Mutex unlock
 
-        match err.kind() {
-            ErrorKind::WouldBlock => {
-                trace!("HydrogenSocket.send received WouldBlock");
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let msg = server::close_panicked(&self.arc_connection, method, &*payload);
+                return Err(HydrogenError::HandlerPanic(method, msg));
+            }
+        };
+
+        if result.is_ok() {
+            self.arc_connection.touch();
+        }
+
+        match result {
+            Ok(SendStatus::Written) => {
+                trace!("HydrogenSocket.send OK");
+                Ok(SendStatus::Written)
+            }
+            Ok(SendStatus::Queued) => {
+                trace!("HydrogenSocket.send queued");
+
+                // Anything already queued is waiting on an EPOLLOUT the fd is armed for
+                if !was_backlogged {
+                    self.arc_connection.rearm(libc::EPOLLOUT);
+                }
 
-                let execute = self.rearm_fn;
-                unsafe {
-                    execute(&(self.arc_connection), libc::EPOLLOUT);
+                if now_backpressured {
+                    let id = self.arc_connection.id;
+                    let handler = &self.arc_connection.handler;
+                    debug!("Connection {} reached its high watermark", id);
+                    let result = server::call_handler(handler, Some(id), "on_backpressure", || {
+                        handler.on_backpressure(id)
+                    });
+                    if let Err((callback, msg)) = result {
+                        self.arc_connection.close_with(server::panic_error(callback, msg));
+                    }
                 }
+
+                Ok(SendStatus::Queued)
             }
-            _ => {
+            Err(err) => {
                 trace!("HydrogenSocket.send received err");
 
+                let ret_err = Error::new(err.kind(), err.to_string());
                 { // Mutex lock
                     let mut err_state = match self.arc_connection.err_mutex.lock() {
                         Ok(g) => g,
@@ -145,20 +619,368 @@ impl HydrogenSocket {
                     };
                     *err_state = Some(err);
                 } // Mutex unlock
+
+                self.arc_connection.epoll.wake();
+
+                Err(HydrogenError::Io(ret_err))
             }
         }
     }
 
-    pub fn shutdown(&mut self) -> Result<(), Error> {
-        let stream_ptr = self.arc_connection.stream.get();
-        unsafe {
-            (*stream_ptr).shutdown()
+    /// Runs `callback` on the I/O threadpool once `after` has passed.
+    ///
+    /// The timer is cancelled if the connection is removed first. A panic in `callback` removes
+    /// the connection, as a panic in any `Handler` callback would.
+    pub fn schedule<F>(&self, after: Duration, callback: F) -> TimerHandle
+        where F: FnOnce(HydrogenSocket<C>) + Send + 'static
+    {
+        let socket = self.clone();
+        let mut callback = Some(callback);
+        let timer = ScheduledTimer::new(Some(self.arc_connection.clone()), None, Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback(socket.clone());
+            }
+        }));
+
+        self.arc_connection.scheduler.schedule_timer(after, timer)
+    }
+
+    /// Runs `callback` on the I/O threadpool every `interval`, measured from the end of the
+    /// previous run, until cancelled or the connection is removed.
+    pub fn schedule_repeating<F>(&self, interval: Duration, mut callback: F) -> TimerHandle
+        where F: FnMut(HydrogenSocket<C>) + Send + 'static
+    {
+        let socket = self.clone();
+        let timer = ScheduledTimer::new(Some(self.arc_connection.clone()),
+                                        Some(interval),
+                                        Box::new(move || callback(socket.clone())));
+
+        self.arc_connection.scheduler.schedule_timer(interval, timer)
+    }
+
+    /// Returns the id of the connection this socket represents.
+    pub fn id(&self) -> ConnectionId {
+        self.arc_connection.id
+    }
+
+    /// Returns the credentials of the peer process, if the connection was accepted on a Unix
+    /// listener.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.arc_connection.peer_credentials
+    }
+
+    /// Removes the connection from the server, discarding anything the stream has not yet been
+    /// able to write.
+    ///
+    /// The fd is closed by the event loop, and `reason` is what `Handler::on_connection_removed`
+    /// receives. Has no effect if the connection is already being removed, the original reason
+    /// is reported instead.
+    pub fn close(&self, reason: Error) {
+        trace!("HydrogenSocket.close");
+        self.arc_connection.close_with(reason);
+    }
+
+    /// Removes the connection from the server once everything previously sent has been flushed
+    /// to the kernel.
+    ///
+    /// Further sends fail with `Error::ConnectionClosed`. If flushing fails, the connection is
+    /// removed with that failure as the reason instead of `reason`.
+    pub fn close_after_flush(&self, reason: Error) {
+        { // Mutex lock
+            let mut tx_state = match self.arc_connection.tx_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            if tx_state.is_backlogged() {
+                trace!("HydrogenSocket.close_after_flush waiting on backlog");
+                if tx_state.pending_close.is_none() {
+                    tx_state.pending_close = Some(reason);
+                }
+                return;
+            }
+        } // Mutex unlock
+
+        self.close(reason);
+    }
+
+    pub fn shutdown(&self) -> Result<(), Error> {
+        let mut stream = match self.arc_connection.stream.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        // The fd of an error'd connection may already have been closed, and even reused
+        if self.arc_connection.is_errored() {
+            return Err(Error::new(ErrorKind::NotConnected, "Connection closed"));
         }
+
+        stream.shutdown()
     }
 }
 
-impl AsRawFd for HydrogenSocket {
+impl<C> AsRawFd for HydrogenSocket<C> {
     fn as_raw_fd(&self) -> RawFd {
         self.arc_connection.fd
     }
 }
+
+/// A successor process waiting for the server's sockets, and the listeners to send it.
+pub struct PendingHandoff {
+    pub stream: UnixStream,
+    pub listeners: Vec<(ListenerId, Arc<Listener>)>
+}
+
+/// Shared between the ServerHandle and the server's threads to coordinate stopping.
+pub struct Shutdown {
+    /// Raised once the server should stop
+    flag: AtomicBool,
+    /// The listening sockets, owned by their listener loops so each is closed, and its socket
+    /// file removed, as soon as its loop finishes
+    listeners: Vec<(ListenerId, Weak<Listener>)>,
+    /// Socket a successor connects to in order to take over, owned by its own loop
+    handoff_listener: Option<Weak<Listener>>,
+    /// Set when a successor has connected, and taken by the event loop as it shuts down
+    handoff: Mutex<Option<PendingHandoff>>,
+    /// Epoll instance of every reactor, each woken so its event loop sees the flag
+    epolls: Vec<Arc<Epoll>>
+}
+
+impl Shutdown {
+    pub fn new(listeners: Vec<(ListenerId, Weak<Listener>)>,
+               handoff_listener: Option<Weak<Listener>>,
+               epolls: Vec<Arc<Epoll>>)
+               -> Shutdown
+    {
+        Shutdown {
+            flag: AtomicBool::new(false),
+            listeners,
+            handoff_listener,
+            handoff: Mutex::new(None),
+            epolls
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Shuts the server down, handing its sockets to the successor on the other end of
+    /// `stream` rather than closing them.
+    ///
+    /// Returns false, and drops `stream`, if the server is already shutting down.
+    pub fn hand_off(&self, stream: UnixStream) -> bool {
+        { // Mutex lock
+            let mut handoff = match self.handoff.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+
+            if self.is_triggered() {
+                return false;
+            }
+
+            // Held on to, as their loops drop them once interrupted
+            let listeners = self.listeners.iter()
+                .filter_map(|&(id, ref listener)| listener.upgrade().map(|l| (id, l)))
+                .collect();
+            *handoff = Some(PendingHandoff {
+                stream,
+                listeners
+            });
+        } // Mutex unlock
+
+        self.trigger();
+        true
+    }
+
+    /// Returns the successor to hand off to, if one has connected.
+    pub fn take_handoff(&self) -> Option<PendingHandoff> {
+        let mut handoff = match self.handoff.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        handoff.take()
+    }
+
+    /// Raises the flag, interrupts every listener loop's blocking accept call, and wakes every
+    /// event loop so the server begins shutting down immediately.
+    pub fn trigger(&self) {
+        if self.flag.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        debug!("Interrupting listeners");
+        let listeners = self.listeners.iter().map(|(_, listener)| listener);
+        for listener in listeners.chain(self.handoff_listener.iter()) {
+            if let Some(listener) = listener.upgrade() {
+                listener.interrupt();
+            }
+        }
+
+        for epoll in self.epolls.iter() {
+            epoll.wake();
+        }
+    }
+}
+
+/// Handle to a running server, returned from `hydrogen::begin`.
+///
+/// Handles are cheap to clone, and every method may be called from any thread. `C` is the
+/// `Handler::Context` type of the server.
+pub struct ServerHandle<C = ()> {
+    /// Shutdown state shared with the server's threads
+    shutdown: Arc<Shutdown>,
+    /// The server's connection pool, split between its reactors
+    shards: ConnectionShards,
+    /// Connection groups, for broadcasting
+    groups: Groups,
+    /// Hands server-level timers to the first reactor's event loop
+    scheduler: Scheduler,
+    /// Adds connections made through `connect` to each reactor in turn
+    connector: Acceptor,
+    /// The first reactor's event loop thread, which is the last to finish during shutdown
+    event_loop: Arc<Mutex<Option<JoinHandle<()>>>>,
+    context_type: PhantomData<fn() -> C>
+}
+
+impl<C> Clone for ServerHandle<C> {
+    fn clone(&self) -> ServerHandle<C> {
+        ServerHandle {
+            shutdown: self.shutdown.clone(),
+            shards: self.shards.clone(),
+            groups: self.groups.clone(),
+            scheduler: self.scheduler.clone(),
+            connector: self.connector.clone(),
+            event_loop: self.event_loop.clone(),
+            context_type: PhantomData
+        }
+    }
+}
+
+impl<C: 'static> ServerHandle<C> {
+    pub fn new(shutdown: Arc<Shutdown>,
+               shards: ConnectionShards,
+               groups: Groups,
+               scheduler: Scheduler,
+               connector: Acceptor,
+               event_loop: JoinHandle<()>)
+               -> ServerHandle<C>
+    {
+        ServerHandle {
+            shutdown,
+            shards,
+            groups,
+            scheduler,
+            connector,
+            event_loop: Arc::new(Mutex::new(Some(event_loop))),
+            context_type: PhantomData
+        }
+    }
+
+    /// Returns a socket for the connection with `id`, or None if it is no longer connected.
+    ///
+    /// The returned socket may be kept and used from any thread. Once the connection is removed,
+    /// sends on it are dropped.
+    pub fn socket(&self, id: ConnectionId) -> Option<HydrogenSocket<C>> {
+        self.shards.find(id).map(|arc_connection| {
+            HydrogenSocket::new(arc_connection)
+        })
+    }
+
+    /// Returns an iterator over sockets for every current connection.
+    ///
+    /// The iterator is a snapshot taken at the time of the call, and does not hold up any event
+    /// loop while it is consumed.
+    pub fn connections(&self) -> ::std::vec::IntoIter<HydrogenSocket<C>> {
+        let sockets: Vec<HydrogenSocket<C>> = self.shards.all().into_iter()
+            .map(HydrogenSocket::new)
+            .collect();
+
+        sockets.into_iter()
+    }
+
+    /// Starts connecting to `addr`, and returns the id the new connection is known by.
+    ///
+    /// `stream_factory` is called right away, with the id and the connecting fd, to create the
+    /// connection's stream and context, as `Handler::on_new_connection` does for accepted
+    /// connections. The connect then finishes on one of the server's event loops, and its
+    /// outcome is reported to `Handler::on_connect_result`. From there on, the connection is
+    /// serviced exactly as an accepted one would be. Anything sent before the connect has
+    /// finished is queued until it has.
+    ///
+    /// A connect still in progress once `Config::idle_timeout` has passed fails with
+    /// `ErrorKind::TimedOut`. Failing to create the socket, or a connect that fails right away,
+    /// returns `Error::Connect`, as does calling this once the server is shutting down.
+    pub fn connect<F>(&self, addr: SocketAddr, stream_factory: F)
+        -> Result<ConnectionId, HydrogenError>
+        where F: FnOnce(ConnectionId, RawFd) -> (Box<dyn Stream>, C),
+              C: Send + Sync
+    {
+        server::connect(&self.connector, &self.shutdown, &addr, |id, fd| {
+            let (stream, context) = stream_factory(id, fd);
+            let context: Box<dyn Any + Send + Sync> = Box::new(context);
+            (stream, context)
+        })
+    }
+
+    /// Returns the server's connection groups.
+    pub fn groups(&self) -> &Groups {
+        &self.groups
+    }
+
+    /// Runs `callback` on the I/O threadpool once `after` has passed.
+    ///
+    /// Timers still waiting when the server shuts down never run.
+    pub fn schedule<F>(&self, after: Duration, callback: F) -> TimerHandle
+        where F: FnOnce() + Send + 'static
+    {
+        let mut callback = Some(callback);
+        let timer = ScheduledTimer::new(None, None, Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        }));
+
+        self.scheduler.schedule_timer(after, timer)
+    }
+
+    /// Runs `callback` on the I/O threadpool every `interval`, measured from the end of the
+    /// previous run, until cancelled or the server shuts down.
+    ///
+    /// A panic in `callback` is reported to `Handler::on_handler_panic`, and the timer keeps
+    /// repeating.
+    pub fn schedule_repeating<F>(&self, interval: Duration, callback: F) -> TimerHandle
+        where F: FnMut() + Send + 'static
+    {
+        let timer = ScheduledTimer::new(None, Some(interval), Box::new(callback));
+
+        self.scheduler.schedule_timer(interval, timer)
+    }
+
+    /// Signals the server to stop.
+    ///
+    /// New connections are no longer accepted, in-flight I/O is allowed to finish, then every
+    /// connection is closed and reported through `Handler::on_connection_removed`. This method
+    /// does not block, use `join` to wait for the shutdown to complete.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Blocks until the server has completely stopped.
+    ///
+    /// This will block forever unless `shutdown` has been, or will be, called, or the server hits
+    /// a fatal error reported through `Handler::on_server_error`. May be called from any number
+    /// of clones of the handle, all of them return once the server has stopped.
+    pub fn join(&self) {
+        let mut event_loop = match self.event_loop.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        if let Some(thread) = event_loop.take() {
+            let _ = thread.join();
+        }
+    }
+}